@@ -1,20 +1,57 @@
 use midir::{MidiInput, Ignore};
+use std::env;
 use std::error::Error;
 
+// Pick a port with `midi_key_mapper --port <selector>`, where the selector is part of
+// the port name, `exact:<name>` or `index:<n>`. Repeat --port to give fallbacks.
+// Without --port the first port is used. The available ports are listed at startup.
+// This is a smaller copy of my_midi's port_select.rs, which also takes regex:<pattern>
+// and opens every matching port. Keep the two in step when the syntax changes.
+fn port_matches(selector: &str, index: usize, name: &str) -> bool {
+    if let Some(exact) = selector.strip_prefix("exact:") {
+        name == exact
+    } else if let Some(wanted) = selector.strip_prefix("index:") {
+        wanted.trim().parse() == Ok(index)
+    } else {
+        name.to_lowercase().contains(&selector.to_lowercase())
+    }
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
+    let mut selectors = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => selectors.push(args.next().ok_or("--port needs a port name, exact:<name> or index:<n>")?),
+            other => return Err(format!("Unknown argument '{}'", other).into()),
+        }
+    }
+
     let mut midi_in = MidiInput::new("midi_reader_input")?;
     midi_in.ignore(Ignore::None);
 
 
-    // Loop until a MIDI input port is available
+    // Loop until a matching MIDI input port is available
     let mut in_port = None;
     while in_port.is_none() {
         let ports = midi_in.ports();
-        if !ports.is_empty() {
-            in_port = Some(ports[0].clone()); // Assuming we want the first available port
+        let names: Vec<String> = ports.iter().map(|port| midi_in.port_name(port).unwrap_or_default()).collect();
+        for (index, name) in names.iter().enumerate() {
+            println!("index:{}  {}", index, name);
+        }
+        let chosen = if selectors.is_empty() {
+            if ports.is_empty() { None } else { Some(0) }
         } else {
-            println!("No MIDI input ports available. Waiting for connection...");
-            std::thread::sleep(std::time::Duration::from_secs(5)); // Wait for 5 seconds before checking again
+            selectors.iter().find_map(|selector| {
+                names.iter().enumerate().position(|(index, name)| port_matches(selector, index, name))
+            })
+        };
+        match chosen {
+            Some(index) => in_port = Some(ports[index].clone()),
+            None => {
+                println!("No matching MIDI input port available. Waiting for connection...");
+                std::thread::sleep(std::time::Duration::from_secs(5)); // Wait for 5 seconds before checking again
+            },
         }
     }
 
@@ -0,0 +1,118 @@
+// device_watcher.rs
+use std::collections::HashSet;
+use std::error::Error;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use midir::MidiInput;
+/*###############################################################################
+Device Watcher
+    Notices MIDI ports appearing and disappearing and tells everyone who
+    subscribed, so a device can reconnect as soon as it is plugged back in
+    instead of waiting for the next poll.
+    On Linux this listens to the ALSA sequencer's announce port, which reports
+    every port and client that starts or exits. Everywhere else (or if ALSA
+    can't be opened) the port list is polled a few times a second.
+###############################################################################*/
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceEvent {
+    /// A port with this name showed up.
+    Connected(String),
+    /// A port with this name went away.
+    Disconnected(String),
+}
+
+pub struct DeviceWatcher {
+    subscribers: Arc<Mutex<Vec<Sender<DeviceEvent>>>>,
+}
+
+impl DeviceWatcher {
+    pub fn start() -> Result<Self, Box<dyn Error>> {
+        let subscribers: Arc<Mutex<Vec<Sender<DeviceEvent>>>> = Arc::new(Mutex::new(Vec::new()));
+        let midi_in = MidiInput::new("midi_device_watcher")?;
+        // Ports that are already there when we start don't get a Connected event
+        let mut known = port_names(&midi_in);
+
+        let subscribers_for_thread = subscribers.clone();
+        thread::spawn(move || {
+            let mut rescan = move || {
+                let current = port_names(&midi_in);
+                for name in current.difference(&known) {
+                    publish(&subscribers_for_thread, DeviceEvent::Connected(name.clone()));
+                }
+                for name in known.difference(&current) {
+                    publish(&subscribers_for_thread, DeviceEvent::Disconnected(name.clone()));
+                }
+                known = current;
+            };
+
+            #[cfg(target_os = "linux")]
+            if let Err(e) = alsa_announce::watch(&mut rescan) {
+                println!("Can't listen for ALSA announcements ({}). Polling for MIDI devices instead.", e);
+            }
+
+            loop {
+                thread::sleep(POLL_INTERVAL);
+                rescan();
+            }
+        });
+
+        Ok(DeviceWatcher { subscribers })
+    }
+
+    /// Every connect and disconnect from now on is sent to the returned receiver.
+    pub fn subscribe(&self) -> Receiver<DeviceEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+}
+
+fn port_names(midi_in: &MidiInput) -> HashSet<String> {
+    midi_in.ports().iter().filter_map(|port| midi_in.port_name(port).ok()).collect()
+}
+
+fn publish(subscribers: &Mutex<Vec<Sender<DeviceEvent>>>, event: DeviceEvent) {
+    // Drop subscribers that have gone away
+    subscribers.lock().unwrap().retain(|tx| tx.send(event.clone()).is_ok());
+}
+
+#[cfg(target_os = "linux")]
+mod alsa_announce {
+    use std::error::Error;
+    use std::ffi::CString;
+    use alsa::seq::{Addr, EventType, PortCap, PortSubscribe, PortType, Seq};
+
+    /// Calls `on_change` every time the sequencer announces a client or port
+    /// starting, exiting or changing. Only returns if the sequencer fails.
+    pub fn watch(on_change: &mut dyn FnMut()) -> Result<(), Box<dyn Error>> {
+        let seq = Seq::open(None, Some(alsa::Direction::Capture), false)?;
+        seq.set_client_name(&CString::new("MyMIDI device watcher")?)?;
+        let port = seq.create_simple_port(
+            &CString::new("announce listener")?,
+            PortCap::WRITE | PortCap::NO_EXPORT,
+            PortType::MIDI_GENERIC | PortType::APPLICATION,
+        )?;
+
+        let subscription = PortSubscribe::empty()?;
+        subscription.set_sender(Addr::system_announce());
+        subscription.set_dest(Addr { client: seq.client_id()?, port });
+        seq.subscribe_port(&subscription)?;
+
+        let mut input = seq.input();
+        loop {
+            let event = input.event_input()?;
+            match event.get_type() {
+                EventType::ClientStart
+                | EventType::ClientExit
+                | EventType::PortStart
+                | EventType::PortExit
+                | EventType::PortChange => on_change(),
+                _ => {},
+            }
+        }
+    }
+}
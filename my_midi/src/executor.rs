@@ -0,0 +1,146 @@
+// executor.rs
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+/*###############################################################################
+Executor
+    Runs slow work (launching apps, opening the browser, Sonar HTTP requests)
+    on background threads so it never holds up the next MIDI message.
+
+    `spawn` hands a job to a small pool of worker threads. Jobs can finish in
+    any order.
+    `spawn_in_lane` runs jobs one after another, in the order they were given,
+    on a thread dedicated to that lane. Use it when order matters, e.g. volume
+    changes for one channel, where an older value must never land last.
+###############################################################################*/
+const WORKER_COUNT: usize = 4;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+#[derive(Clone)]
+pub struct Executor {
+    pool: Arc<Mutex<Sender<Job>>>,
+    lanes: Arc<Mutex<HashMap<String, Sender<Job>>>>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..WORKER_COUNT {
+            let rx = rx.clone();
+            thread::spawn(move || loop {
+                // Only hold the lock while waiting for a job, not while running it
+                let job = rx.lock().unwrap().recv();
+                match job {
+                    Ok(job) => run_job(job),
+                    Err(_) => break,
+                }
+            });
+        }
+        Executor {
+            pool: Arc::new(Mutex::new(tx)),
+            lanes: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn spawn(&self, job: impl FnOnce() + Send + 'static) {
+        let _ = self.pool.lock().unwrap().send(Box::new(job));
+    }
+
+    pub fn spawn_in_lane(&self, lane: &str, job: impl FnOnce() + Send + 'static) {
+        let mut lanes = self.lanes.lock().unwrap();
+        let sender = lanes.entry(lane.to_string()).or_insert_with(|| {
+            let (tx, rx) = mpsc::channel::<Job>();
+            thread::spawn(move || run_lane(rx));
+            tx
+        });
+        let _ = sender.send(Box::new(job));
+    }
+}
+
+fn run_lane(rx: Receiver<Job>) {
+    for job in rx {
+        run_job(job);
+    }
+}
+
+// A job that panics (e.g. a bug in a profile's background work) shouldn't
+// take its worker thread down with it.
+fn run_job(job: Job) {
+    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+        println!("A background job panicked.");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn lanes_run_jobs_in_order() {
+        let executor = Executor::new();
+        let (tx, rx) = mpsc::channel();
+        for i in 0..100 {
+            let tx = tx.clone();
+            executor.spawn_in_lane("game", move || {
+                // Early jobs take longer, so any reordering would show
+                if i < 5 {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                tx.send(i).unwrap();
+            });
+        }
+        let order: Vec<i32> = (0..100).map(|_| rx.recv_timeout(WAIT).unwrap()).collect();
+        assert_eq!(order, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn lanes_do_not_wait_for_each_other() {
+        let executor = Executor::new();
+        let (tx, rx) = mpsc::channel();
+        let (release, blocked) = mpsc::channel::<()>();
+        executor.spawn_in_lane("game", move || {
+            let _ = blocked.recv_timeout(WAIT);
+        });
+        let done = tx.clone();
+        executor.spawn_in_lane("chat", move || done.send("chat").unwrap());
+        assert_eq!(rx.recv_timeout(WAIT), Ok("chat"));
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn the_pool_runs_every_job() {
+        let executor = Executor::new();
+        let (tx, rx) = mpsc::channel();
+        for i in 0..20 {
+            let tx = tx.clone();
+            executor.spawn(move || tx.send(i).unwrap());
+        }
+        let mut done: Vec<i32> = (0..20).map(|_| rx.recv_timeout(WAIT).unwrap()).collect();
+        done.sort();
+        assert_eq!(done, (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn panicking_jobs_leave_their_threads_running() {
+        let executor = Executor::new();
+        let (tx, rx) = mpsc::channel();
+        // More panics than there are workers, so a dead worker would be noticed
+        for _ in 0..WORKER_COUNT * 2 {
+            executor.spawn(|| panic!("pool job"));
+        }
+        executor.spawn_in_lane("game", || panic!("lane job"));
+        let pool_done = tx.clone();
+        executor.spawn(move || pool_done.send("pool").unwrap());
+        executor.spawn_in_lane("game", move || tx.send("lane").unwrap());
+        let mut done = vec![rx.recv_timeout(WAIT).unwrap(), rx.recv_timeout(WAIT).unwrap()];
+        done.sort();
+        assert_eq!(done, vec!["lane", "pool"]);
+    }
+}
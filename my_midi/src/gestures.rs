@@ -0,0 +1,311 @@
+// gestures.rs
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+use crate::midi_message::MidiMessage;
+/*###############################################################################
+Gestures
+    A pad can do more than one thing. Add "gesture" to a trigger and it fires on
+    that gesture instead of on every press:
+        { "trigger": { "status": 153, "note": 36, "gesture": "tap" }, ... }
+        { "trigger": { "status": 153, "note": 36, "gesture": "long_press" }, ... }
+    Gestures:
+        tap          pressed and released quickly
+        double_tap   pressed twice in a row
+        long_press   held down. Fires once, while the pad is still down.
+        hold_repeat  held down. Fires at the long press and then again and again
+                     until the pad is let go, like holding a key on a keyboard.
+    So taps don't go off by accident:
+        - a tap only fires on release, and not after a long press,
+        - when the pad also has a double_tap, a tap waits to see whether a second
+          press is coming.
+    The press's velocity is matched against "value" (or "velocity"), so a soft
+    and a hard hit can be two different gestures.
+
+    Timing can be changed in settings.json:
+        "gestures": { "long_press_ms": 500, "double_tap_ms": 250, "repeat_ms": 100 }
+###############################################################################*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Gesture {
+    Tap,
+    DoubleTap,
+    LongPress,
+    HoldRepeat,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GestureSettings {
+    /// How long a pad has to be held for a long press.
+    pub long_press_ms: u64,
+    /// How soon after letting go the second press of a double tap has to come.
+    pub double_tap_ms: u64,
+    /// How often hold_repeat fires after the long press.
+    pub repeat_ms: u64,
+}
+
+impl Default for GestureSettings {
+    fn default() -> Self {
+        GestureSettings { long_press_ms: 500, double_tap_ms: 250, repeat_ms: 100 }
+    }
+}
+
+/// A recognized gesture and the press that started it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GestureEvent {
+    pub gesture: Gesture,
+    pub press: MidiMessage,
+}
+
+enum PadState {
+    Held {
+        since: Instant,
+        /// The second press of a double tap. It doesn't count as anything else.
+        second: bool,
+        long_press_done: bool,
+        next_repeat: Option<Instant>,
+    },
+    /// Let go after a short press, waiting to see if a double tap follows.
+    Released { at: Instant },
+}
+
+struct Pad {
+    press: MidiMessage,
+    /// The gestures bound to the pad when it was pressed.
+    bound: Vec<Gesture>,
+    state: PadState,
+}
+
+/// Turns a device's note ons and offs into gestures. Pads are told apart by
+/// channel and note.
+pub struct GestureRecognizer {
+    long_press: Duration,
+    double_tap: Duration,
+    repeat: Duration,
+    pads: HashMap<(u8, u8), Pad>,
+}
+
+impl GestureRecognizer {
+    pub fn new(settings: &GestureSettings) -> Self {
+        GestureRecognizer {
+            long_press: Duration::from_millis(settings.long_press_ms),
+            double_tap: Duration::from_millis(settings.double_tap_ms),
+            // A zero interval would repeat forever without waiting
+            repeat: Duration::from_millis(settings.repeat_ms.max(10)),
+            pads: HashMap::new(),
+        }
+    }
+
+    /// Feeds a message in. `bound` are the gestures bound to the pad it comes
+    /// from; pads without any aren't tracked.
+    pub fn handle(&mut self, message: &MidiMessage, bound: &[Gesture], now: Instant) -> Vec<GestureEvent> {
+        match *message {
+            MidiMessage::NoteOn { channel, note, .. } if !bound.is_empty() => self.press((channel, note), message, bound, now),
+            MidiMessage::NoteOff { channel, note, .. } => self.release((channel, note), now),
+            _ => Vec::new(),
+        }
+    }
+
+    fn press(&mut self, pad: (u8, u8), message: &MidiMessage, bound: &[Gesture], now: Instant) -> Vec<GestureEvent> {
+        let mut events = Vec::new();
+        if let Some(previous) = self.pads.remove(&pad) {
+            if let PadState::Released { at } = previous.state {
+                if now < at + self.double_tap {
+                    events.push(GestureEvent { gesture: Gesture::DoubleTap, press: previous.press.clone() });
+                    self.pads.insert(pad, Pad {
+                        press: previous.press,
+                        bound: previous.bound,
+                        state: PadState::Held { since: now, second: true, long_press_done: true, next_repeat: None },
+                    });
+                    return events;
+                }
+                // The double tap window ran out before anyone looked
+                events.push(GestureEvent { gesture: Gesture::Tap, press: previous.press });
+            }
+        }
+        let next_repeat = bound.contains(&Gesture::HoldRepeat).then(|| now + self.long_press);
+        self.pads.insert(pad, Pad {
+            press: message.clone(),
+            bound: bound.to_vec(),
+            state: PadState::Held { since: now, second: false, long_press_done: false, next_repeat },
+        });
+        events
+    }
+
+    fn release(&mut self, pad: (u8, u8), now: Instant) -> Vec<GestureEvent> {
+        let Some(mut state) = self.pads.remove(&pad) else {
+            return Vec::new();
+        };
+        match state.state {
+            PadState::Held { since, second: false, long_press_done: false, .. } if now < since + self.long_press => {
+                if state.bound.contains(&Gesture::DoubleTap) {
+                    state.state = PadState::Released { at: now };
+                    self.pads.insert(pad, state);
+                    Vec::new()
+                } else {
+                    vec![GestureEvent { gesture: Gesture::Tap, press: state.press }]
+                }
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    /// Gestures that are due by `now`: long presses, repeats and taps whose
+    /// double tap window is over.
+    pub fn poll(&mut self, now: Instant) -> Vec<GestureEvent> {
+        let mut events = Vec::new();
+        let mut tapped = Vec::new();
+        for (key, pad) in self.pads.iter_mut() {
+            match &mut pad.state {
+                PadState::Held { since, second: false, long_press_done, next_repeat } => {
+                    if !*long_press_done && now >= *since + self.long_press {
+                        *long_press_done = true;
+                        events.push(GestureEvent { gesture: Gesture::LongPress, press: pad.press.clone() });
+                    }
+                    while let Some(due) = *next_repeat {
+                        if now < due {
+                            break;
+                        }
+                        events.push(GestureEvent { gesture: Gesture::HoldRepeat, press: pad.press.clone() });
+                        *next_repeat = Some(due + self.repeat);
+                    }
+                },
+                PadState::Released { at } if now >= *at + self.double_tap => {
+                    events.push(GestureEvent { gesture: Gesture::Tap, press: pad.press.clone() });
+                    tapped.push(*key);
+                },
+                _ => {},
+            }
+        }
+        for key in tapped {
+            self.pads.remove(&key);
+        }
+        events
+    }
+
+    /// When `poll` next has something to do, if ever.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pads.values()
+            .filter_map(|pad| match pad.state {
+                PadState::Held { since, second: false, long_press_done, next_repeat } => {
+                    let long_press = (!long_press_done).then(|| since + self.long_press);
+                    [long_press, next_repeat].into_iter().flatten().min()
+                },
+                PadState::Released { at } => Some(at + self.double_tap),
+                _ => None,
+            })
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Gesture; 4] = [Gesture::Tap, Gesture::DoubleTap, Gesture::LongPress, Gesture::HoldRepeat];
+
+    fn on(velocity: u8) -> MidiMessage {
+        MidiMessage::NoteOn { channel: 10, note: 36, velocity }
+    }
+
+    fn off() -> MidiMessage {
+        MidiMessage::NoteOff { channel: 10, note: 36, velocity: 0 }
+    }
+
+    fn ms(start: Instant, ms: u64) -> Instant {
+        start + Duration::from_millis(ms)
+    }
+
+    fn gestures(events: Vec<GestureEvent>) -> Vec<Gesture> {
+        events.into_iter().map(|event| event.gesture).collect()
+    }
+
+    fn recognizer() -> GestureRecognizer {
+        GestureRecognizer::new(&GestureSettings::default())
+    }
+
+    #[test]
+    fn tap_fires_on_release() {
+        let (mut pads, t) = (recognizer(), Instant::now());
+        let bound = [Gesture::Tap, Gesture::LongPress];
+        assert!(pads.handle(&on(90), &bound, t).is_empty());
+        let events = pads.handle(&off(), &bound, ms(t, 120));
+        assert_eq!(events, vec![GestureEvent { gesture: Gesture::Tap, press: on(90) }]);
+        assert_eq!(pads.next_deadline(), None);
+    }
+
+    #[test]
+    fn long_press_fires_while_held_and_is_not_a_tap() {
+        let (mut pads, t) = (recognizer(), Instant::now());
+        let bound = [Gesture::Tap, Gesture::LongPress];
+        pads.handle(&on(90), &bound, t);
+        assert_eq!(pads.next_deadline(), Some(ms(t, 500)));
+        assert!(pads.poll(ms(t, 499)).is_empty());
+        assert_eq!(gestures(pads.poll(ms(t, 500))), vec![Gesture::LongPress]);
+        assert!(pads.poll(ms(t, 900)).is_empty());
+        assert!(pads.handle(&off(), &bound, ms(t, 1000)).is_empty());
+    }
+
+    #[test]
+    fn late_release_without_poll_is_not_a_tap() {
+        let (mut pads, t) = (recognizer(), Instant::now());
+        pads.handle(&on(90), &[Gesture::Tap], t);
+        assert!(pads.handle(&off(), &[Gesture::Tap], ms(t, 800)).is_empty());
+    }
+
+    #[test]
+    fn double_tap_holds_back_the_tap() {
+        let (mut pads, t) = (recognizer(), Instant::now());
+        pads.handle(&on(90), &ALL, t);
+        assert!(pads.handle(&off(), &ALL, ms(t, 80)).is_empty());
+        assert_eq!(gestures(pads.handle(&on(90), &ALL, ms(t, 200))), vec![Gesture::DoubleTap]);
+        // The second press counts for nothing else, however long it's held
+        assert!(pads.poll(ms(t, 2000)).is_empty());
+        assert!(pads.handle(&off(), &ALL, ms(t, 2100)).is_empty());
+        assert_eq!(pads.next_deadline(), None);
+    }
+
+    #[test]
+    fn tap_fires_when_no_second_press_comes() {
+        let (mut pads, t) = (recognizer(), Instant::now());
+        let bound = [Gesture::Tap, Gesture::DoubleTap];
+        pads.handle(&on(90), &bound, t);
+        pads.handle(&off(), &bound, ms(t, 80));
+        assert_eq!(pads.next_deadline(), Some(ms(t, 330)));
+        assert!(pads.poll(ms(t, 300)).is_empty());
+        assert_eq!(gestures(pads.poll(ms(t, 330))), vec![Gesture::Tap]);
+
+        // A press after the window starts over, and still gets the missed tap out
+        pads.handle(&on(90), &bound, ms(t, 1000));
+        pads.handle(&off(), &bound, ms(t, 1050));
+        assert_eq!(gestures(pads.handle(&on(90), &bound, ms(t, 1400))), vec![Gesture::Tap]);
+    }
+
+    #[test]
+    fn hold_repeat_repeats_until_release() {
+        let (mut pads, t) = (recognizer(), Instant::now());
+        let bound = [Gesture::HoldRepeat];
+        pads.handle(&on(90), &bound, t);
+        assert_eq!(gestures(pads.poll(ms(t, 500))), vec![Gesture::LongPress, Gesture::HoldRepeat]);
+        assert_eq!(pads.next_deadline(), Some(ms(t, 600)));
+        assert_eq!(gestures(pads.poll(ms(t, 820))), vec![Gesture::HoldRepeat, Gesture::HoldRepeat, Gesture::HoldRepeat]);
+        pads.handle(&off(), &bound, ms(t, 850));
+        assert!(pads.poll(ms(t, 2000)).is_empty());
+    }
+
+    #[test]
+    fn pads_without_gestures_are_ignored() {
+        let (mut pads, t) = (recognizer(), Instant::now());
+        assert!(pads.handle(&on(90), &[], t).is_empty());
+        assert!(pads.handle(&off(), &[], ms(t, 10)).is_empty());
+        assert_eq!(pads.next_deadline(), None);
+    }
+
+    #[test]
+    fn keeps_the_press_velocity() {
+        let (mut pads, t) = (recognizer(), Instant::now());
+        pads.handle(&on(20), &[Gesture::LongPress], t);
+        assert_eq!(pads.poll(ms(t, 500)), vec![GestureEvent { gesture: Gesture::LongPress, press: on(20) }]);
+    }
+}
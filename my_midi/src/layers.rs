@@ -0,0 +1,181 @@
+// layers.rs
+use serde::Deserialize;
+use crate::midi_message::MidiMessage;
+/*###############################################################################
+Layers
+    The MPK Mini Play doesn't have many pads, so a pad can be turned into a
+    modifier, like Shift on a keyboard: while its layer is on, the other pads
+    do something else. Give a trigger a layer and it only fires while that
+    layer is on:
+        { "trigger": { "status": 153, "note": 36, "layer": "shift" }, ... }
+    A message goes to the topmost layer that binds the pad, knob or key it came
+    from. Pads the layer doesn't bind fall through to the layers below it, and
+    finally to the bindings without a layer. So a layer only has to list what
+    it changes.
+    Gestures (see gestures.rs) go to the layers that are on when they fire, so
+    keep the modifier held until a tap or long press has gone off.
+
+    Layer pads:
+        { "type": "layer", "layer": "shift" }
+            On while the pad is held (momentary, the default).
+        { "type": "layer", "layer": "fn", "mode": "latch" }
+            Press once to turn it on, again to turn it off.
+        { "type": "cycle_layers", "layers": ["lows", "highs"] }
+            Each press turns on the next layer in the list, and after the last
+            one turns them all off again.
+    Profiles can look at `ctx.layers.active()` for things that aren't
+    bindings, like Genshin's music layouts.
+
+    Every device has its own layers. Changing profiles turns them all off.
+###############################################################################*/
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LayerMode {
+    #[default]
+    Momentary,
+    Latch,
+}
+
+#[derive(Debug)]
+struct Entry {
+    name: String,
+    /// The channel and note of the pad holding a momentary layer on.
+    held_by: Option<(u8, u8)>,
+}
+
+/// The layers that are on, in the order they were turned on.
+#[derive(Debug, Default)]
+pub struct LayerStack {
+    entries: Vec<Entry>,
+}
+
+impl LayerStack {
+    /// Turns `name` on until the pad that sent `press` is let go.
+    pub fn hold(&mut self, name: &str, press: &MidiMessage) {
+        let held_by = match *press {
+            MidiMessage::NoteOn { channel, note, .. } => Some((channel, note)),
+            _ => None,
+        };
+        if held_by.is_none() {
+            println!("A momentary layer needs a pad or key to hold. Turning '{}' on until it is pressed again.", name);
+            self.toggle(name);
+            return;
+        }
+        self.entries.push(Entry { name: name.to_string(), held_by });
+    }
+
+    /// Turns off the layers held on by the pad `release` comes from.
+    pub fn release(&mut self, release: &MidiMessage) {
+        if let MidiMessage::NoteOff { channel, note, .. } = *release {
+            self.entries.retain(|entry| entry.held_by != Some((channel, note)));
+        }
+    }
+
+    /// Turns a latched layer on, or off if it was on. Returns whether it is on now.
+    pub fn toggle(&mut self, name: &str) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|entry| !(entry.name == name && entry.held_by.is_none()));
+        if self.entries.len() < before {
+            return false;
+        }
+        self.entries.push(Entry { name: name.to_string(), held_by: None });
+        true
+    }
+
+    /// Latches the layer after the one in `names` that is on now, or none after
+    /// the last one. Returns the layer that is on now.
+    pub fn cycle<'a>(&mut self, names: &'a [String]) -> Option<&'a str> {
+        let current = names.iter().position(|name| self.entries.iter().any(|entry| entry.name == *name && entry.held_by.is_none()));
+        self.entries.retain(|entry| !(entry.held_by.is_none() && names.contains(&entry.name)));
+        let next = match current {
+            Some(index) => names.get(index + 1)?,
+            None => names.first()?,
+        };
+        self.entries.push(Entry { name: next.clone(), held_by: None });
+        Some(next)
+    }
+
+    /// The layers that are on, topmost (most recently turned on) first.
+    pub fn active(&self) -> Vec<&str> {
+        let mut active: Vec<&str> = Vec::new();
+        for entry in self.entries.iter().rev() {
+            if !active.contains(&entry.name.as_str()) {
+                active.push(&entry.name);
+            }
+        }
+        active
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(note: u8) -> MidiMessage {
+        MidiMessage::NoteOn { channel: 10, note, velocity: 100 }
+    }
+
+    fn release(note: u8) -> MidiMessage {
+        MidiMessage::NoteOff { channel: 10, note, velocity: 0 }
+    }
+
+    #[test]
+    fn momentary_layers_last_while_held() {
+        let mut layers = LayerStack::default();
+        layers.hold("shift", &press(36));
+        layers.hold("fn", &press(37));
+        assert_eq!(layers.active(), vec!["fn", "shift"]);
+        layers.release(&release(99));
+        layers.release(&release(36));
+        assert_eq!(layers.active(), vec!["fn"]);
+        layers.release(&release(37));
+        assert!(layers.active().is_empty());
+    }
+
+    #[test]
+    fn latched_layers_toggle() {
+        let mut layers = LayerStack::default();
+        assert!(layers.toggle("fn"));
+        layers.hold("shift", &press(36));
+        assert_eq!(layers.active(), vec!["shift", "fn"]);
+        layers.release(&release(36));
+        assert_eq!(layers.active(), vec!["fn"]);
+        assert!(!layers.toggle("fn"));
+        assert!(layers.active().is_empty());
+    }
+
+    #[test]
+    fn holding_a_latched_layer_keeps_it_latched() {
+        let mut layers = LayerStack::default();
+        layers.toggle("fn");
+        layers.hold("fn", &press(36));
+        layers.release(&release(36));
+        assert_eq!(layers.active(), vec!["fn"]);
+    }
+
+    #[test]
+    fn cycles_through_layers_and_back_to_none() {
+        let names = vec![String::from("lows"), String::from("highs")];
+        let mut layers = LayerStack::default();
+        layers.toggle("fn");
+        assert_eq!(layers.cycle(&names), Some("lows"));
+        assert_eq!(layers.cycle(&names), Some("highs"));
+        assert_eq!(layers.active(), vec!["highs", "fn"]);
+        assert_eq!(layers.cycle(&names), None);
+        assert_eq!(layers.active(), vec!["fn"]);
+        assert_eq!(layers.cycle(&names), Some("lows"));
+    }
+
+    #[test]
+    fn knobs_cannot_hold_a_layer() {
+        let mut layers = LayerStack::default();
+        layers.hold("shift", &MidiMessage::ControlChange { channel: 1, controller: 70, value: 127 });
+        assert_eq!(layers.active(), vec!["shift"]);
+        layers.hold("shift", &MidiMessage::ControlChange { channel: 1, controller: 70, value: 0 });
+        assert!(layers.active().is_empty());
+    }
+}
@@ -0,0 +1,648 @@
+// macro_recorder.rs
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use enigo::{Button, Key};
+use serde_json::{json, Value};
+use crate::macros::Step;
+use crate::mappings;
+use crate::midi_commands;
+use crate::midi_message::MidiMessage;
+/*###############################################################################
+Macro Recorder
+    Records what you type and click and turns it into a macro (see macros.rs):
+        1. Hit the pad bound to { "type": "record_macro" }. Recording starts.
+        2. Do the thing. Keys, mouse buttons, mouse moves and the scroll wheel are
+           recorded with their timing.
+        3. Hit the record pad again to stop.
+        4. Hit the pad the macro should go on. The binding is saved to
+           `recorded_<profile>.json` in the mappings directory, for the active
+           profile, and works right away. Recording again instead throws it away.
+    Hitting the same pad again later replaces its recorded macro. Edit or delete
+    the file to tidy up; it's a normal mapping file.
+
+    Recording reads the keyboards and mice under /dev/input, so it only works on
+    Linux, for a user who can read them (usually the "input" group):
+        sudo usermod -aG input $USER
+    Keys without a name that works on every platform (see `key_for`) are left out.
+###############################################################################*/
+enum State {
+    Idle,
+    Recording(evdev::Recording),
+    WaitingForPad(Vec<Step>),
+}
+
+pub struct MacroRecorder {
+    state: Mutex<State>,
+}
+
+impl Default for MacroRecorder {
+    fn default() -> Self {
+        MacroRecorder::new()
+    }
+}
+
+impl MacroRecorder {
+    pub fn new() -> Self {
+        MacroRecorder { state: Mutex::new(State::Idle) }
+    }
+
+    /// Starts recording, or stops and waits for the pad to bind the macro to.
+    pub fn toggle(&self) {
+        let mut state = self.state.lock().unwrap();
+        *state = match std::mem::replace(&mut *state, State::Idle) {
+            State::Recording(recording) => {
+                let (steps, skipped) = to_steps(&recording.stop());
+                if skipped > 0 {
+                    println!("Left {} key presses out of the macro, they have no portable name.", skipped);
+                }
+                if steps.is_empty() {
+                    midi_commands::show_toast("Macro Recorder", "Nothing was recorded.");
+                    State::Idle
+                } else {
+                    midi_commands::show_toast("Macro Recorder", &format!("Recorded {} steps. Hit the pad to put the macro on.", steps.len()));
+                    State::WaitingForPad(steps)
+                }
+            },
+            State::Idle | State::WaitingForPad(_) => match evdev::Recording::start() {
+                Ok(recording) => {
+                    midi_commands::show_toast("Macro Recorder", "Recording. Hit the record pad again to stop.");
+                    State::Recording(recording)
+                },
+                Err(e) => {
+                    midi_commands::show_error("Macro Recorder", &format!("Could not start recording: {}", e));
+                    State::Idle
+                },
+            },
+        };
+    }
+
+    /// If a recorded macro is waiting for a pad and `message` is a pad press,
+    /// binds the macro to that pad in `profile`. Returns true if it did, in which
+    /// case the pad's other actions shouldn't run. `record_pad` says the pad is
+    /// bound to "record_macro". That pad never gets the macro; hitting it records
+    /// again instead.
+    pub fn bind_to_pad(&self, message: &MidiMessage, profile: &str, record_pad: bool) -> bool {
+        let (Some(status), Some(note)) = (message.status(), message.note()) else {
+            return false;
+        };
+        if record_pad || !matches!(message, MidiMessage::NoteOn { velocity, .. } if *velocity > 0) {
+            return false;
+        }
+        let steps = {
+            let mut state = self.state.lock().unwrap();
+            match std::mem::replace(&mut *state, State::Idle) {
+                State::WaitingForPad(steps) => steps,
+                other => {
+                    *state = other;
+                    return false;
+                },
+            }
+        };
+        match save_binding(&mappings::mappings_dir(), profile, status, note, &steps) {
+            Ok(path) => {
+                println!("Saved the recorded macro to {}", path.display());
+                midi_commands::show_toast("Macro Recorder", &format!("Macro saved to pad {} in {}.", note, profile));
+            },
+            Err(e) => midi_commands::show_error("Macro Recorder", &format!("Could not save the macro: {}", e)),
+        }
+        true
+    }
+}
+
+/// The file recorded macros for `profile` are saved to.
+pub fn recorded_file(dir: &Path, profile: &str) -> PathBuf {
+    let name: String = profile.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect();
+    dir.join(format!("recorded_{}.json", name))
+}
+
+/// Binds `steps` to the pad that sent `status` and `note`, for `profile`,
+/// replacing a macro recorded for that pad before. Returns the file written.
+pub fn save_binding(dir: &Path, profile: &str, status: u8, note: u8, steps: &[Step]) -> Result<PathBuf, Box<dyn Error>> {
+    let path = recorded_file(dir, profile);
+    let mut file: Value = if path.exists() {
+        let contents = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        serde_json::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e))?
+    } else {
+        json!({ "profile": profile, "bindings": [] })
+    };
+    let bindings = file.get_mut("bindings").and_then(Value::as_array_mut)
+        .ok_or_else(|| format!("{}: there is no \"bindings\" list", path.display()))?;
+
+    let trigger = json!({ "status": status, "note": note });
+    bindings.retain(|binding| binding.get("trigger") != Some(&trigger));
+    bindings.push(json!({
+        "trigger": trigger,
+        "action": { "type": "macro", "steps": steps },
+    }));
+    fs::write(&path, serde_json::to_string_pretty(&file)?).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+/*###############################################################################
+Turning input events into steps
+    Events are the kernel's: a type, a code and a value. Steps get a sleep in
+    front of them when the gap since the last one is long enough to matter.
+    Mouse movement arrives in tiny pieces, so it is added up and played back as
+    one relative move per MOVE_SLICE.
+###############################################################################*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputEvent {
+    /// When it happened, from the kernel's clock.
+    pub time: Duration,
+    pub kind: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const REL_X: u16 = 0x00;
+const REL_Y: u16 = 0x01;
+const REL_HWHEEL: u16 = 0x06;
+const REL_WHEEL: u16 = 0x08;
+const BTN_LEFT: u16 = 0x110;
+const BTN_RIGHT: u16 = 0x111;
+const BTN_MIDDLE: u16 = 0x112;
+
+/// Shorter pauses than this aren't worth a sleep step.
+const MIN_SLEEP: Duration = Duration::from_millis(10);
+/// Mouse movement within this long becomes one move.
+const MOVE_SLICE: Duration = Duration::from_millis(16);
+
+/// The steps that replay `events`, and how many key presses had to be left out.
+pub fn to_steps(events: &[InputEvent]) -> (Vec<Step>, usize) {
+    let mut events = events.to_vec();
+    // Keyboard and mouse are read separately, so put them back in order
+    events.sort_by_key(|event| event.time);
+
+    let mut builder = StepBuilder::default();
+    let mut skipped = 0;
+    for event in &events {
+        match (event.kind, event.code) {
+            (EV_KEY, code) if event.value == 0 || event.value == 1 => {
+                let pressed = event.value == 1;
+                let step = match (button_for(code), key_for(code)) {
+                    (Some(button), _) if pressed => Step::MousePress(button),
+                    (Some(button), _) => Step::MouseRelease(button),
+                    (None, Some(key)) if pressed => Step::KeyPress(key),
+                    (None, Some(key)) => Step::KeyRelease(key),
+                    (None, None) => {
+                        skipped += pressed as usize;
+                        continue;
+                    },
+                };
+                builder.push(step, event.time);
+            },
+            (EV_REL, REL_X) => builder.move_by(event.value, 0, event.time),
+            (EV_REL, REL_Y) => builder.move_by(0, event.value, event.time),
+            // The wheel counts up when scrolling up; a scroll step scrolls down for positive amounts
+            (EV_REL, REL_WHEEL) => builder.push(Step::Scroll { amount: -event.value, horizontal: false }, event.time),
+            (EV_REL, REL_HWHEEL) => builder.push(Step::Scroll { amount: event.value, horizontal: true }, event.time),
+            _ => {},
+        }
+    }
+    builder.flush_move();
+    (builder.steps, skipped)
+}
+
+#[derive(Default)]
+struct StepBuilder {
+    steps: Vec<Step>,
+    /// When the last step happened.
+    last: Option<Duration>,
+    /// Movement added up so far, and when it started.
+    moving: Option<(i32, i32, Duration)>,
+}
+
+impl StepBuilder {
+    fn push(&mut self, step: Step, time: Duration) {
+        self.flush_move();
+        self.push_at(step, time);
+    }
+
+    fn push_at(&mut self, step: Step, time: Duration) {
+        if let Some(last) = self.last {
+            let gap = time.saturating_sub(last);
+            if gap >= MIN_SLEEP {
+                self.steps.push(Step::Sleep(gap.as_millis() as u64));
+            }
+        }
+        self.steps.push(step);
+        self.last = Some(time);
+    }
+
+    fn move_by(&mut self, dx: i32, dy: i32, time: Duration) {
+        match self.moving {
+            Some((x, y, started)) if time.saturating_sub(started) < MOVE_SLICE => self.moving = Some((x + dx, y + dy, started)),
+            _ => {
+                self.flush_move();
+                self.moving = Some((dx, dy, time));
+            },
+        }
+    }
+
+    fn flush_move(&mut self) {
+        if let Some((x, y, started)) = self.moving.take() {
+            self.push_at(Step::MouseMove { x, y, relative: true }, started);
+        }
+    }
+}
+
+fn button_for(code: u16) -> Option<Button> {
+    match code {
+        BTN_LEFT => Some(Button::Left),
+        BTN_RIGHT => Some(Button::Right),
+        BTN_MIDDLE => Some(Button::Middle),
+        _ => None,
+    }
+}
+
+/// The key for a Linux key code (see linux/input-event-codes.h). Only keys that
+/// exist on every platform, so a recorded macro plays back anywhere.
+fn key_for(code: u16) -> Option<Key> {
+    const ROW_1: &str = "1234567890-=";
+    const ROW_Q: &str = "qwertyuiop[]";
+    const ROW_A: &str = "asdfghjkl;'`";
+    const ROW_Z: &str = "\\zxcvbnm,./";
+    let in_row = |row: &str, first: u16| row.chars().nth(code.checked_sub(first)? as usize).map(Key::Unicode);
+    let key = match code {
+        1 => Key::Escape,
+        2..=13 => return in_row(ROW_1, 2),
+        14 => Key::Backspace,
+        15 => Key::Tab,
+        16..=27 => return in_row(ROW_Q, 16),
+        28 => Key::Return,
+        29 => Key::LControl,
+        30..=41 => return in_row(ROW_A, 30),
+        42 => Key::LShift,
+        43..=53 => return in_row(ROW_Z, 43),
+        54 => Key::RShift,
+        56 | 100 => Key::Alt,
+        57 => Key::Space,
+        58 => Key::CapsLock,
+        59 => Key::F1,
+        60 => Key::F2,
+        61 => Key::F3,
+        62 => Key::F4,
+        63 => Key::F5,
+        64 => Key::F6,
+        65 => Key::F7,
+        66 => Key::F8,
+        67 => Key::F9,
+        68 => Key::F10,
+        87 => Key::F11,
+        88 => Key::F12,
+        97 => Key::RControl,
+        102 => Key::Home,
+        103 => Key::UpArrow,
+        104 => Key::PageUp,
+        105 => Key::LeftArrow,
+        106 => Key::RightArrow,
+        107 => Key::End,
+        108 => Key::DownArrow,
+        109 => Key::PageDown,
+        111 => Key::Delete,
+        113 => Key::VolumeMute,
+        114 => Key::VolumeDown,
+        115 => Key::VolumeUp,
+        125 | 126 => Key::Meta,
+        163 => Key::MediaNextTrack,
+        164 => Key::MediaPlayPause,
+        165 => Key::MediaPrevTrack,
+        _ => return None,
+    };
+    Some(key)
+}
+
+/*###############################################################################
+Reading /dev/input
+    /proc/bus/input/devices lists every input device and its handlers. The ones
+    with a "kbd" or "mouse" handler are opened without blocking and read on one
+    thread, which waits on all of them with poll(). It checks for the stop a few
+    times a second, reads whatever is still waiting, then closes them all.
+###############################################################################*/
+/// The /dev/input/eventN names of keyboards and mice in /proc/bus/input/devices.
+#[cfg(target_os = "linux")]
+pub fn keyboards_and_mice(devices: &str) -> Vec<String> {
+    devices.lines()
+        .filter_map(|line| line.strip_prefix("H: Handlers="))
+        .filter(|handlers| handlers.split_whitespace().any(|handler| handler == "kbd" || handler.starts_with("mouse")))
+        .filter_map(|handlers| handlers.split_whitespace().find(|handler| handler.starts_with("event")))
+        .map(|event| event.to_string())
+        .collect()
+}
+
+/// Size of the kernel's struct input_event: a timeval, then type, code and value.
+#[cfg(target_os = "linux")]
+const EVENT_SIZE: usize = if cfg!(target_pointer_width = "64") { 24 } else { 16 };
+
+/// Reads one struct input_event.
+#[cfg(target_os = "linux")]
+pub fn parse_event(bytes: &[u8]) -> Option<InputEvent> {
+    if bytes.len() < EVENT_SIZE {
+        return None;
+    }
+    let word = EVENT_SIZE / 2 - 4;
+    let number = |at: usize| -> u64 {
+        match word {
+            8 => u64::from_ne_bytes(bytes[at..at + 8].try_into().unwrap()),
+            _ => u32::from_ne_bytes(bytes[at..at + 4].try_into().unwrap()) as u64,
+        }
+    };
+    let (seconds, micros) = (number(0), number(word));
+    let rest = &bytes[2 * word..];
+    Some(InputEvent {
+        time: Duration::from_secs(seconds) + Duration::from_micros(micros),
+        kind: u16::from_ne_bytes([rest[0], rest[1]]),
+        code: u16::from_ne_bytes([rest[2], rest[3]]),
+        value: i32::from_ne_bytes([rest[4], rest[5], rest[6], rest[7]]),
+    })
+}
+
+#[cfg(target_os = "linux")]
+mod evdev {
+    use std::error::Error;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, Read};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+    use super::{keyboards_and_mice, parse_event, InputEvent, EVENT_SIZE};
+
+    /// How long the reader waits for input before checking whether to stop.
+    const POLL_TIMEOUT_MS: i32 = 100;
+
+    pub struct Recording {
+        stop: Arc<AtomicBool>,
+        reader: JoinHandle<Vec<InputEvent>>,
+    }
+
+    impl Recording {
+        pub fn start() -> Result<Self, Box<dyn Error>> {
+            let devices = fs::read_to_string("/proc/bus/input/devices")?;
+            let mut files = Vec::new();
+            let mut last_error = None;
+            for name in keyboards_and_mice(&devices) {
+                let path = format!("/dev/input/{}", name);
+                match OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(&path) {
+                    Ok(file) => files.push(file),
+                    Err(e) => last_error = Some(format!("{}: {}", path, e)),
+                }
+            }
+            if files.is_empty() {
+                let reason = last_error.unwrap_or_else(|| String::from("no keyboard or mouse found"));
+                return Err(format!("{} (is your user in the \"input\" group?)", reason).into());
+            }
+            let stop = Arc::new(AtomicBool::new(false));
+            let stop_for_thread = stop.clone();
+            let reader = thread::spawn(move || read_until_stopped(files, &stop_for_thread));
+            Ok(Recording { stop, reader })
+        }
+
+        /// Stops recording and returns everything recorded. The devices are closed
+        /// by the time it returns.
+        pub fn stop(self) -> Vec<InputEvent> {
+            self.stop.store(true, Ordering::SeqCst);
+            self.reader.join().unwrap_or_default()
+        }
+    }
+
+    /// Reads every device on one thread, waiting on all of them at once. Once told
+    /// to stop, it reads whatever is still waiting and closes them.
+    pub(super) fn read_until_stopped(mut files: Vec<File>, stop: &AtomicBool) -> Vec<InputEvent> {
+        let mut events = Vec::new();
+        let mut fds: Vec<libc::pollfd> = files.iter().map(|file| libc::pollfd { fd: file.as_raw_fd(), events: libc::POLLIN, revents: 0 }).collect();
+        loop {
+            let stopping = stop.load(Ordering::SeqCst);
+            let timeout = if stopping { 0 } else { POLL_TIMEOUT_MS };
+            let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+            if ready < 0 && io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                println!("Stopped recording input: {}", io::Error::last_os_error());
+                return events;
+            }
+            for (file, fd) in files.iter_mut().zip(fds.iter_mut()) {
+                if fd.revents != 0 && !read_waiting(file, &mut events) {
+                    // Unplugged. poll skips negative fds.
+                    fd.fd = -1;
+                }
+            }
+            if stopping {
+                return events;
+            }
+        }
+    }
+
+    /// Reads every event waiting on `file`. Returns false if the device is gone.
+    fn read_waiting(file: &mut File, events: &mut Vec<InputEvent>) -> bool {
+        let mut buffer = [0u8; EVENT_SIZE * 64];
+        loop {
+            match file.read(&mut buffer) {
+                Ok(0) => return false,
+                // The kernel only ever hands out whole events
+                Ok(read) => events.extend(buffer[..read].chunks_exact(EVENT_SIZE).filter_map(parse_event)),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(_) => return false,
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod evdev {
+    use std::error::Error;
+    use super::InputEvent;
+
+    pub struct Recording;
+
+    impl Recording {
+        pub fn start() -> Result<Self, Box<dyn Error>> {
+            Err("recording macros only works on Linux".into())
+        }
+
+        pub fn stop(self) -> Vec<InputEvent> {
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(ms: u64, kind: u16, code: u16, value: i32) -> InputEvent {
+        InputEvent { time: Duration::from_millis(1_700_000_000_000 + ms), kind, code, value }
+    }
+
+    #[test]
+    fn records_keys_with_timing() {
+        let events = [
+            event(0, EV_KEY, 56, 1),   // Alt down
+            event(5, EV_KEY, 25, 1),   // p down
+            event(5, EV_KEY, 25, 2),   // autorepeat
+            event(8, EV_KEY, 25, 0),
+            event(250, EV_KEY, 56, 0), // Alt up
+            event(260, EV_KEY, 240, 1), // KEY_UNKNOWN
+            event(261, EV_KEY, 240, 0),
+        ];
+        let (steps, skipped) = to_steps(&events);
+        assert_eq!(steps, vec![
+            Step::KeyPress(Key::Alt),
+            Step::KeyPress(Key::Unicode('p')),
+            Step::KeyRelease(Key::Unicode('p')),
+            Step::Sleep(242),
+            Step::KeyRelease(Key::Alt),
+        ]);
+        assert_eq!(skipped, 1);
+    }
+
+    #[test]
+    fn adds_up_mouse_movement() {
+        let mut events = vec![event(100, EV_KEY, BTN_LEFT, 1)];
+        for ms in 0..10 {
+            events.push(event(110 + ms, EV_REL, REL_X, 2));
+            events.push(event(110 + ms, EV_REL, REL_Y, -1));
+        }
+        events.push(event(200, EV_REL, REL_X, 5));
+        events.push(event(300, EV_KEY, BTN_LEFT, 0));
+        events.push(event(310, EV_REL, REL_WHEEL, 1));
+        // The mouse and keyboard are read one after the other, so they can come out of order
+        events.reverse();
+        let (steps, _) = to_steps(&events);
+        assert_eq!(steps, vec![
+            Step::MousePress(Button::Left),
+            Step::Sleep(10),
+            Step::MouseMove { x: 20, y: -10, relative: true },
+            Step::Sleep(90),
+            Step::MouseMove { x: 5, y: 0, relative: true },
+            Step::Sleep(100),
+            Step::MouseRelease(Button::Left),
+            Step::Sleep(10),
+            Step::Scroll { amount: -1, horizontal: false },
+        ]);
+    }
+
+    #[test]
+    fn maps_key_codes() {
+        assert_eq!(key_for(2), Some(Key::Unicode('1')));
+        assert_eq!(key_for(11), Some(Key::Unicode('0')));
+        assert_eq!(key_for(16), Some(Key::Unicode('q')));
+        assert_eq!(key_for(38), Some(Key::Unicode('l')));
+        assert_eq!(key_for(41), Some(Key::Unicode('`')));
+        assert_eq!(key_for(43), Some(Key::Unicode('\\')));
+        assert_eq!(key_for(44), Some(Key::Unicode('z')));
+        assert_eq!(key_for(53), Some(Key::Unicode('/')));
+        assert_eq!(key_for(55), None);
+        assert_eq!(key_for(88), Some(Key::F12));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn finds_keyboards_and_mice() {
+        let devices = "\
+I: Bus=0011 Vendor=0001 Product=0001 Version=ab41
+N: Name=\"AT Translated Set 2 keyboard\"
+H: Handlers=sysrq kbd leds event3
+B: EV=120013
+
+I: Bus=0003 Vendor=046d Product=c52b Version=0111
+N: Name=\"Logitech USB Receiver\"
+H: Handlers=mouse0 event5
+
+I: Bus=0019 Vendor=0000 Product=0001 Version=0000
+N: Name=\"Power Button\"
+H: Handlers=event0
+";
+        assert_eq!(keyboards_and_mice(devices), vec!["event3", "event5"]);
+    }
+
+    /// A struct input_event for pressing `code`, as the kernel hands it out.
+    #[cfg(target_os = "linux")]
+    fn kernel_event(code: u16) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for number in [1_700_000_000u64, 250_000] {
+            match EVENT_SIZE {
+                24 => bytes.extend_from_slice(&number.to_ne_bytes()),
+                _ => bytes.extend_from_slice(&(number as u32).to_ne_bytes()),
+            }
+        }
+        bytes.extend_from_slice(&EV_KEY.to_ne_bytes());
+        bytes.extend_from_slice(&code.to_ne_bytes());
+        bytes.extend_from_slice(&1i32.to_ne_bytes());
+        bytes
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn parses_kernel_events() {
+        let bytes = kernel_event(30);
+        assert_eq!(parse_event(&bytes), Some(InputEvent { time: Duration::from_millis(1_700_000_000_250), kind: EV_KEY, code: 30, value: 1 }));
+        assert_eq!(parse_event(&bytes[1..]), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn stopping_keeps_waiting_events_and_closes_the_devices() {
+        use std::fs::File;
+        use std::io::Write;
+        use std::os::unix::io::FromRawFd;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        // A pipe stands in for /dev/input/eventN
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK) }, 0);
+        let (device, mut input) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_for_thread = stop.clone();
+        let reader = std::thread::spawn(move || evdev::read_until_stopped(vec![device], &stop_for_thread));
+
+        input.write_all(&[kernel_event(30), kernel_event(31)].concat()).unwrap();
+        stop.store(true, Ordering::SeqCst);
+        let codes: Vec<u16> = reader.join().unwrap().iter().map(|event| event.code).collect();
+        assert_eq!(codes, vec![30, 31]);
+        // Nobody reads the other end anymore
+        assert!(input.write_all(&kernel_event(32)).is_err());
+    }
+
+    #[test]
+    fn the_record_pad_never_gets_the_macro() {
+        let recorder = MacroRecorder { state: Mutex::new(State::WaitingForPad(vec![Step::Text(String::from("gg"))])) };
+        let record_pad = MidiMessage::NoteOn { channel: 10, note: 44, velocity: 90 };
+        assert!(!recorder.bind_to_pad(&record_pad, "Default", true));
+        assert!(!recorder.bind_to_pad(&MidiMessage::NoteOff { channel: 10, note: 40, velocity: 0 }, "Default", false));
+        // Still waiting, so the record pad's own action can record again
+        assert!(matches!(*recorder.state.lock().unwrap(), State::WaitingForPad(ref steps) if steps.len() == 1));
+    }
+
+    #[test]
+    fn saves_and_replaces_bindings() {
+        let dir = std::env::temp_dir().join(format!("mymidi-recorder-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let first = vec![Step::KeyPress(Key::Alt), Step::KeyClick(Key::Unicode('p')), Step::KeyRelease(Key::Alt)];
+        let second = vec![Step::Text(String::from("gg"))];
+
+        let path = save_binding(&dir, "Zenless Zone Zero", 153, 40, &first).unwrap();
+        assert_eq!(path, dir.join("recorded_zenless_zone_zero.json"));
+        save_binding(&dir, "Zenless Zone Zero", 153, 41, &first).unwrap();
+        save_binding(&dir, "Zenless Zone Zero", 153, 40, &second).unwrap();
+
+        // It has to load like any other mapping file
+        let file: crate::mappings::MappingFile = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(file.profile.as_deref(), Some("Zenless Zone Zero"));
+        assert_eq!(file.bindings.len(), 2);
+        assert_eq!(file.bindings[1].trigger.note, Some(40));
+        match &file.bindings[1].action {
+            crate::mappings::Action::Macro { steps } => assert_eq!(*steps, second),
+            other => panic!("expected a macro, got {:?}", other),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,404 @@
+// macros.rs
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use enigo::{
+    Axis, Button, Coordinate,
+    Direction::{self, Click, Press, Release},
+    Enigo, Key, Keyboard, Mouse,
+};
+use crate::midi_commands;
+/*###############################################################################
+Macros
+    A macro is a list of steps a pad plays back in order:
+        { "type": "macro", "steps": [
+            { "key_press": "Alt" },
+            { "key_click": { "Unicode": "p" } },
+            { "key_release": "Alt" }
+        ] }
+    Steps:
+        { "key_press": "Shift" }        hold a key down until a key_release
+        { "key_release": "Shift" }
+        { "key_click": "Return" }       press and release
+        { "chord": ["Control", "Shift", { "Unicode": "s" }] }
+                                        press them in order, release them in reverse
+        { "text": "gg wp" }             type text
+        { "mouse_move": { "x": 960, "y": 540 } }
+        { "mouse_move": { "x": 0, "y": -50, "relative": true } }
+        { "mouse_press": "Left" }, { "mouse_release": "Left" }, { "mouse_click": "Right" }
+        { "scroll": { "amount": 3 } }   positive scrolls down. Add "horizontal": true
+                                        to scroll sideways (positive is right).
+        { "sleep": 250 }                wait, in milliseconds
+        { "launch": "C:\\Windows\\notepad.exe" }
+        { "open_url": "https://www.hoyolab.com/" }
+    Keys are written the same way as for key_click actions (see mappings.rs).
+    Instead of writing one by hand, you can record one (see macro_recorder.rs).
+
+    Macros run one at a time on their own thread, so a long one never holds up
+    the next MIDI message. Hitting the pad of a macro that is running (or still
+    waiting its turn) cancels it. However a macro ends (finished, cancelled or
+    failed), every key and mouse button it still holds is released.
+###############################################################################*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    KeyPress(Key),
+    KeyRelease(Key),
+    KeyClick(Key),
+    Chord(Vec<Key>),
+    Text(String),
+    MouseMove {
+        x: i32,
+        y: i32,
+        #[serde(default)]
+        relative: bool,
+    },
+    MousePress(Button),
+    MouseRelease(Button),
+    MouseClick(Button),
+    Scroll {
+        amount: i32,
+        #[serde(default)]
+        horizontal: bool,
+    },
+    Sleep(u64),
+    Launch(String),
+    OpenUrl(String),
+}
+
+type InputResult = Result<(), String>;
+
+/// What macros type and click with. Enigo in the app, a recorder in the tests.
+pub trait Input {
+    fn key(&mut self, key: Key, direction: Direction) -> InputResult;
+    fn text(&mut self, text: &str) -> InputResult;
+    fn move_mouse(&mut self, x: i32, y: i32, coordinate: Coordinate) -> InputResult;
+    fn button(&mut self, button: Button, direction: Direction) -> InputResult;
+    fn scroll(&mut self, amount: i32, axis: Axis) -> InputResult;
+}
+
+impl Input for Enigo {
+    fn key(&mut self, key: Key, direction: Direction) -> InputResult {
+        Keyboard::key(self, key, direction).map_err(|e| e.to_string())
+    }
+
+    fn text(&mut self, text: &str) -> InputResult {
+        Keyboard::text(self, text).map_err(|e| e.to_string())
+    }
+
+    fn move_mouse(&mut self, x: i32, y: i32, coordinate: Coordinate) -> InputResult {
+        Mouse::move_mouse(self, x, y, coordinate).map_err(|e| e.to_string())
+    }
+
+    fn button(&mut self, button: Button, direction: Direction) -> InputResult {
+        Mouse::button(self, button, direction).map_err(|e| e.to_string())
+    }
+
+    fn scroll(&mut self, amount: i32, axis: Axis) -> InputResult {
+        Mouse::scroll(self, amount, axis).map_err(|e| e.to_string())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Finished,
+    Cancelled,
+}
+
+/// Macros that are running or waiting their turn, with their cancel flags.
+type Pending = Mutex<Vec<(String, Arc<AtomicBool>)>>;
+
+struct Job {
+    key: String,
+    steps: Vec<Step>,
+    cancel: Arc<AtomicBool>,
+}
+
+/// Plays macros back, one at a time, on a thread of its own.
+pub struct MacroRunner {
+    sender: Sender<Job>,
+    pending: Arc<Pending>,
+}
+
+impl MacroRunner {
+    /// Starts the macro thread. It gets its own Enigo, since the one handling
+    /// MIDI messages has to stay free while a macro sleeps.
+    pub fn start() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let pending = Arc::new(Mutex::new(Vec::new()));
+        let thread_pending = pending.clone();
+        thread::spawn(move || match Enigo::new(&enigo::Settings::default()) {
+            Ok(mut enigo) => run(receiver, &mut enigo, &thread_pending),
+            Err(e) => println!("Macros are disabled, could not set up keyboard and mouse input: {}", e),
+        });
+        MacroRunner { sender, pending }
+    }
+
+    /// Plays `steps`, or cancels them if the macro called `key` is already
+    /// running or waiting.
+    pub fn toggle(&self, key: &str, steps: &[Step]) {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(index) = pending.iter().position(|(pending_key, _)| pending_key == key) {
+            let (_, cancel) = pending.remove(index);
+            cancel.store(true, Ordering::SeqCst);
+            println!("Cancelled macro {}.", key);
+            return;
+        }
+        let cancel = Arc::new(AtomicBool::new(false));
+        pending.push((key.to_string(), cancel.clone()));
+        let _ = self.sender.send(Job { key: key.to_string(), steps: steps.to_vec(), cancel });
+    }
+}
+
+fn run(receiver: Receiver<Job>, input: &mut dyn Input, pending: &Pending) {
+    for job in receiver {
+        if !job.cancel.load(Ordering::SeqCst) {
+            match play(&job.steps, input, &job.cancel) {
+                Ok(_) => {},
+                Err(e) => {
+                    println!("Macro {} failed: {}", job.key, e);
+                    midi_commands::show_error("Macro Failed", &e);
+                },
+            }
+        }
+        pending.lock().unwrap().retain(|(_, cancel)| !Arc::ptr_eq(cancel, &job.cancel));
+    }
+}
+
+/// Keys and mouse buttons a macro has pressed and not released yet.
+#[derive(Default)]
+struct Held {
+    keys: Vec<Key>,
+    buttons: Vec<Button>,
+}
+
+impl Held {
+    fn press_key(&mut self, input: &mut dyn Input, key: Key) -> InputResult {
+        input.key(key, Press)?;
+        if !self.keys.contains(&key) {
+            self.keys.push(key);
+        }
+        Ok(())
+    }
+
+    fn release_key(&mut self, input: &mut dyn Input, key: Key) -> InputResult {
+        self.keys.retain(|held| *held != key);
+        input.key(key, Release)
+    }
+
+    /// Releases everything, last pressed first. Keeps going if one fails.
+    fn release_all(&mut self, input: &mut dyn Input) {
+        while let Some(button) = self.buttons.pop() {
+            let _ = input.button(button, Release);
+        }
+        while let Some(key) = self.keys.pop() {
+            let _ = input.key(key, Release);
+        }
+    }
+}
+
+/// Plays `steps` until they're done, `cancel` is set or a step fails, then
+/// releases whatever is still held. That includes a step panicking.
+fn play(steps: &[Step], input: &mut dyn Input, cancel: &AtomicBool) -> Result<Outcome, String> {
+    let mut held = Held::default();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| play_steps(steps, input, cancel, &mut held)));
+    held.release_all(input);
+    result.unwrap_or_else(|_| Err(String::from("a step panicked")))
+}
+
+fn play_steps(steps: &[Step], input: &mut dyn Input, cancel: &AtomicBool, held: &mut Held) -> Result<Outcome, String> {
+    for step in steps {
+        if cancel.load(Ordering::SeqCst) {
+            return Ok(Outcome::Cancelled);
+        }
+        match step {
+            Step::KeyPress(key) => held.press_key(input, *key)?,
+            Step::KeyRelease(key) => held.release_key(input, *key)?,
+            Step::KeyClick(key) => input.key(*key, Click)?,
+            Step::Chord(keys) => {
+                for key in keys {
+                    held.press_key(input, *key)?;
+                }
+                for key in keys.iter().rev() {
+                    held.release_key(input, *key)?;
+                }
+            },
+            Step::Text(text) => input.text(text)?,
+            Step::MouseMove { x, y, relative } => {
+                input.move_mouse(*x, *y, if *relative { Coordinate::Rel } else { Coordinate::Abs })?
+            },
+            Step::MousePress(button) => {
+                input.button(*button, Press)?;
+                held.buttons.push(*button);
+            },
+            Step::MouseRelease(button) => {
+                held.buttons.retain(|held| held != button);
+                input.button(*button, Release)?;
+            },
+            Step::MouseClick(button) => input.button(*button, Click)?,
+            Step::Scroll { amount, horizontal } => {
+                input.scroll(*amount, if *horizontal { Axis::Horizontal } else { Axis::Vertical })?
+            },
+            Step::Sleep(ms) => {
+                if !sleep(Duration::from_millis(*ms), cancel) {
+                    return Ok(Outcome::Cancelled);
+                }
+            },
+            Step::Launch(path) => midi_commands::launch_exe(path),
+            Step::OpenUrl(url) => {
+                let _ = midi_commands::open_url(url);
+            },
+        }
+    }
+    Ok(Outcome::Finished)
+}
+
+/// Sleeps for `duration`, waking up early if `cancel` is set. Returns false if it was.
+fn sleep(duration: Duration, cancel: &AtomicBool) -> bool {
+    let until = Instant::now() + duration;
+    loop {
+        if cancel.load(Ordering::SeqCst) {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= until {
+            return true;
+        }
+        thread::sleep((until - now).min(Duration::from_millis(10)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes down every call, and fails on `fail_on` if set.
+    #[derive(Default)]
+    struct Recorder {
+        calls: Vec<String>,
+        fail_on: Option<String>,
+        cancel_on: Option<(String, Arc<AtomicBool>)>,
+    }
+
+    impl Recorder {
+        fn record(&mut self, call: String) -> InputResult {
+            if let Some((on, cancel)) = &self.cancel_on {
+                if *on == call {
+                    cancel.store(true, Ordering::SeqCst);
+                }
+            }
+            let failed = self.fail_on.as_deref() == Some(call.as_str());
+            self.calls.push(call);
+            if failed { Err(String::from("input failed")) } else { Ok(()) }
+        }
+    }
+
+    impl Input for Recorder {
+        fn key(&mut self, key: Key, direction: Direction) -> InputResult {
+            self.record(format!("{:?} {:?}", direction, key))
+        }
+
+        fn text(&mut self, text: &str) -> InputResult {
+            if text == "panic" {
+                panic!("enigo panicked");
+            }
+            self.record(format!("text {}", text))
+        }
+
+        fn move_mouse(&mut self, x: i32, y: i32, coordinate: Coordinate) -> InputResult {
+            self.record(format!("move {} {} {:?}", x, y, coordinate))
+        }
+
+        fn button(&mut self, button: Button, direction: Direction) -> InputResult {
+            self.record(format!("{:?} {:?}", direction, button))
+        }
+
+        fn scroll(&mut self, amount: i32, axis: Axis) -> InputResult {
+            self.record(format!("scroll {} {:?}", amount, axis))
+        }
+    }
+
+    fn steps(json: &str) -> Vec<Step> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn plays_steps_in_order() {
+        let steps = steps(r#"[
+            { "key_press": "Alt" },
+            { "key_click": { "Unicode": "p" } },
+            { "key_release": "Alt" },
+            { "chord": ["Control", "Shift", { "Unicode": "s" }] },
+            { "text": "gg" },
+            { "mouse_move": { "x": 10, "y": -5, "relative": true } },
+            { "mouse_click": "Left" },
+            { "scroll": { "amount": -3 } },
+            { "sleep": 1 }
+        ]"#);
+        let mut recorder = Recorder::default();
+        assert_eq!(play(&steps, &mut recorder, &AtomicBool::new(false)), Ok(Outcome::Finished));
+        assert_eq!(recorder.calls, vec![
+            "Press Alt", "Click Unicode('p')", "Release Alt",
+            "Press Control", "Press Shift", "Press Unicode('s')",
+            "Release Unicode('s')", "Release Shift", "Release Control",
+            "text gg", "move 10 -5 Rel", "Click Left", "scroll -3 Vertical",
+        ]);
+    }
+
+    #[test]
+    fn cancelling_releases_held_keys() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mut recorder = Recorder { cancel_on: Some((String::from("Press Unicode('w')"), cancel.clone())), ..Recorder::default() };
+        let steps = steps(r#"[
+            { "key_press": "Shift" },
+            { "mouse_press": "Left" },
+            { "key_press": { "Unicode": "w" } },
+            { "sleep": 60000 },
+            { "key_release": { "Unicode": "w" } },
+            { "key_release": "Shift" }
+        ]"#);
+        let started = Instant::now();
+        assert_eq!(play(&steps, &mut recorder, &cancel), Ok(Outcome::Cancelled));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(recorder.calls[3..], ["Release Left", "Release Unicode('w')", "Release Shift"]);
+    }
+
+    #[test]
+    fn failures_and_panics_release_held_keys() {
+        let steps = steps(r#"[{ "key_press": "Alt" }, { "key_click": "Tab" }, { "key_release": "Alt" }]"#);
+        let mut recorder = Recorder { fail_on: Some(String::from("Click Tab")), ..Recorder::default() };
+        assert!(play(&steps, &mut recorder, &AtomicBool::new(false)).is_err());
+        assert_eq!(recorder.calls, vec!["Press Alt", "Click Tab", "Release Alt"]);
+
+        let steps = vec![Step::KeyPress(Key::Control), Step::Text(String::from("panic"))];
+        let mut recorder = Recorder::default();
+        assert!(play(&steps, &mut recorder, &AtomicBool::new(false)).is_err());
+        assert_eq!(recorder.calls, vec!["Press Control", "Release Control"]);
+    }
+
+    #[test]
+    fn forgotten_releases_are_done_at_the_end() {
+        let steps = steps(r#"[{ "key_press": "Alt" }, { "key_press": "Alt" }, { "mouse_press": "Right" }]"#);
+        let mut recorder = Recorder::default();
+        assert_eq!(play(&steps, &mut recorder, &AtomicBool::new(false)), Ok(Outcome::Finished));
+        assert_eq!(recorder.calls, vec!["Press Alt", "Press Alt", "Press Right", "Release Right", "Release Alt"]);
+    }
+
+    #[test]
+    fn second_hit_cancels() {
+        let (sender, receiver) = mpsc::channel();
+        let runner = MacroRunner { sender, pending: Arc::new(Mutex::new(Vec::new())) };
+        let steps = steps(r#"[{ "sleep": 60000 }]"#);
+        runner.toggle("pad", &steps);
+        runner.toggle("pad", &steps);
+        let job = receiver.try_recv().unwrap();
+        assert!(job.cancel.load(Ordering::SeqCst));
+        assert!(receiver.try_recv().is_err(), "the second hit shouldn't queue another run");
+        runner.toggle("pad", &steps);
+        assert!(!receiver.try_recv().unwrap().cancel.load(Ordering::SeqCst));
+    }
+}
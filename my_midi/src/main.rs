@@ -1,72 +1,252 @@
-use std::collections::HashMap;
-use std::{error::Error, thread, time::Duration};
+use std::{error::Error, thread, time::{Duration, Instant}};
 #[macro_use] extern crate lazy_static;
+use std::collections::HashSet;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
 use std::sync::{Arc, Mutex};
-use enigo::{
-    Direction::{Click,Press,Release},
-    Enigo, Key, Keyboard, Settings,
-};
-use std::fmt;
-use std::env;
+use enigo::Enigo;
 use midir::{MidiInput, Ignore};
 mod profiles;
+mod device_watcher;
+mod executor;
+mod gestures;
+mod layers;
+mod macro_recorder;
+mod macros;
 mod steelseries_sonar_api;
 mod midi_commands;
+mod notifier;
+mod osd;
+mod mappings;
+mod mapping_watcher;
+mod midi_message;
+mod port_select;
+mod settings;
+mod volume;
+mod volume_throttle;
+mod window_watcher;
+use device_watcher::{DeviceEvent, DeviceWatcher};
+use executor::Executor;
+use gestures::{GestureRecognizer, GestureSettings};
+use layers::LayerStack;
+use macro_recorder::MacroRecorder;
+use macros::MacroRunner;
+use mappings::{Action, Mappings};
+use midi_message::{MidiMessage, MidiSource};
+use port_select::PortSelector;
+use profiles::{Context, ProfileRegistry};
+use settings::{DeviceSettings, Settings};
+use steelseries_sonar_api::{DataFlow, Mix, Sonar};
+use volume::{BackendKind, Volume};
+use volume_throttle::VolumeThrottle;
 
 /*###############################################################################
-Profile Delegation 
-Adding a new profile requires a few steps.
-    1. Create `src/profiles/profile_name.rs` with your logic to handle messages.
-    2. Add `pub mod profile_name;` to `src/profiles/mod.rs` so you can import it
-        into main.rs
-    3. Add to enum list in this next section.
-    4. Include it in the profile change button within the main() function.
-    5. Map the profile name to the handle_message function within the main() function.
-       (see near end of main.rs script)
+Profile Delegation
+    Profiles live in `src/profiles`. See `src/profiles/mod.rs` for how to add one.
 ###############################################################################*/
-#[derive(Debug, Clone, Copy)]
-enum Profile {
-    Default,
-    Genshin,
-    ZenlessZoneZero
+fn main() -> Result<(), Box<dyn Error>> {
+    let settings = Settings::from_args()?;
+    notifier::install(&settings.notifications);
+    osd::install(&settings.osd);
+    if settings.list_ports {
+        let midi_in = MidiInput::new("midi_port_lister")?;
+        for (index, port) in midi_in.ports().iter().enumerate() {
+            println!("index:{}  {}", index, midi_in.port_name(port)?);
+        }
+        return Ok(());
+    }
+    if settings.list_sonar_devices {
+        let mut sonar = Sonar::new(settings.sonar.streamer_mode(),None)?;
+        for device in sonar.audio_devices()? {
+            let flow = match device.flow {
+                DataFlow::Render => "output",
+                DataFlow::Capture => "input ",
+            };
+            println!("{}  {}  {}", flow, device.name, device.id);
+        }
+        return Ok(());
+    }
+
+    /*******************************************************************************
+    Button mappings
+        Loaded once from the mappings directory, then reloaded whenever a mapping
+        file changes. The watcher has to stay alive for the life of the app.
+    *******************************************************************************/
+    let mappings_dir = mappings::mappings_dir();
+    let mappings = Mappings::load(&mappings_dir)?;
+    println!("Loaded {} bindings from {}", mappings.binding_count(), mappings_dir.display());
+    let mappings = Arc::new(Mutex::new(mappings));
+    let _mapping_watcher = match mapping_watcher::watch(mappings_dir.clone(), mappings.clone()) {
+        Ok(watcher) => Some(watcher),
+        Err(e) => {
+            println!("Could not watch {} for changes: {}", mappings_dir.display(), e);
+            None
+        },
+    };
+
+    /*******************************************************************************
+    Volume setup
+        Knobs control whichever backend the "volume" settings pick (see volume/mod.rs).
+        SteelSeries Sonar is only set up when it's the backend. I didn't want to
+        run this each time I turn a knob, so I setup Sonar here. Every device shares it.
+    *******************************************************************************/
+    let sonar = if settings.volume.backend == BackendKind::Sonar {
+        match Sonar::new(settings.sonar.streamer_mode(),None) {
+            Ok(mut sonar) => {
+                println!("Sonar is in {} mode.", if sonar.streamer_mode() { "Streamer" } else { "Classic" });
+                match sonar.all_channel_states(Mix::Monitoring) {
+                    Ok(states) => for (channel, state) in states {
+                        println!("Sonar {}: {:.0}%{}", channel, state.volume * 100.0, if state.muted { " (muted)" } else { "" });
+                    },
+                    Err(e) => println!("Could not read the current Sonar volumes: {}", e),
+                }
+                if let Ok(balance) = sonar.get_chat_mix() {
+                    println!("Sonar chat mix: {:.2} (-1 is all game, 1 is all chat)", balance);
+                }
+                Some(Arc::new(Mutex::new(sonar)))
+            },
+            Err(e) => {
+                println!("Could not set up SteelSeries Sonar: {}", e);
+                None
+            },
+        }
+    } else {
+        None
+    };
+    let volume = Arc::new(Volume::new(&settings.volume, sonar.clone()));
+    match volume.get_volume("master", Mix::Monitoring) {
+        Ok(level) => println!("Volume backend: {} (master at {:.0}%)", volume.backend_name(), level * 100.0),
+        Err(e) => println!("Volume backend: {} ({})", volume.backend_name(), e),
+    }
+
+    /*******************************************************************************
+    Background work
+        Launching apps, opening URLs and Sonar requests all run on the
+        executor (see executor.rs), so a slow one never delays the next key press.
+        Knob turns go through the volume throttle so the backend isn't flooded.
+        Macros play on a thread of their own (see macros.rs).
+    *******************************************************************************/
+    let executor = Executor::new();
+    let updates_per_second = settings.volume_updates_per_second.unwrap_or(volume_throttle::DEFAULT_UPDATES_PER_SECOND);
+    let throttle = Arc::new(VolumeThrottle::new(executor.clone(), updates_per_second));
+    let macros = Arc::new(MacroRunner::start());
+    let recorder = Arc::new(MacroRecorder::new());
+
+    /*******************************************************************************
+    Automatic profiles
+        The window watcher picks profiles by the focused window, using the rules in
+        settings.json (see window_watcher.rs). Each device subscribes once connected.
+    *******************************************************************************/
+    window_watcher::install(&settings.auto_profile, &profiles::registry().names())?;
+
+    /*******************************************************************************
+    MIDI devices
+        Every port a device from settings.json matches gets its own thread that
+        connects to it, and reconnects when it is unplugged, independently of the
+        other ports. The device watcher tells each thread when ports come and go,
+        and tells this thread to start another one when a device matches more
+        ports than it has threads for (see port_select.rs).
+        `claimed_ports` keeps two threads from opening the same port.
+    *******************************************************************************/
+    let device_watcher = DeviceWatcher::start()?;
+    let port_events = device_watcher.subscribe();
+    let claimed_ports = Arc::new(Mutex::new(HashSet::new()));
+    let mut devices = Vec::new();
+    for device in settings.devices() {
+        // Check the selectors up front so a typo stops MyMIDI right away
+        let selectors = device.port_selectors()?;
+        devices.push((device, selectors, 0));
+    }
+    let port_counter = MidiInput::new("midi_port_counter")?;
+    loop {
+        let port_names: Vec<String> = port_counter.ports().iter().map(|port| port_counter.port_name(port).unwrap_or_default()).collect();
+        for (device, selectors, threads) in devices.iter_mut() {
+            // A device with nothing plugged in still gets a thread, to wait for it
+            let wanted = port_select::matching_ports(selectors, &port_names).len().max(1);
+            while *threads < wanted {
+                let device = device.clone();
+                let selectors = selectors.clone();
+                let mappings = mappings.clone();
+                let claimed_ports = claimed_ports.clone();
+                let device_events = device_watcher.subscribe();
+                let services = Services { executor: executor.clone(), throttle: throttle.clone(), volume: volume.clone(), sonar: sonar.clone(), macros: macros.clone(), recorder: recorder.clone(), gestures: settings.gestures.clone() };
+                thread::spawn(move || {
+                    if let Err(e) = run_device(&device, &selectors, mappings, claimed_ports, device_events, services) {
+                        println!("Stopped listening for {}: {}", device_label(&device), e);
+                    }
+                });
+                *threads += 1;
+            }
+        }
+        if port_events.recv().is_err() {
+            return Ok(());
+        }
+        port_events.try_iter().for_each(drop);
+    }
 }
-impl fmt::Display for Profile {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", match self {
-            Profile::Default => "Default",
-            Profile::Genshin => "Genshin",
-            Profile::ZenlessZoneZero => "ZenlessZoneZero",
-        })
+
+/// Shared by every device, and handed to actions and profiles through `Context`.
+#[derive(Clone)]
+struct Services {
+    executor: Executor,
+    throttle: Arc<VolumeThrottle>,
+    volume: Arc<Volume>,
+    sonar: Option<Arc<Mutex<Sonar>>>,
+    macros: Arc<MacroRunner>,
+    recorder: Arc<MacroRecorder>,
+    gestures: GestureSettings,
+}
+
+impl Services {
+    fn context<'a>(&'a self, enigo: &'a mut Enigo, layers: &'a mut LayerStack) -> Context<'a> {
+        Context { enigo, layers, executor: &self.executor, throttle: &self.throttle, volume: &self.volume, sonar: &self.sonar, macros: &self.macros, recorder: &self.recorder }
     }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
+/// What a device's dispatcher thread handles, in the order it arrives.
+enum Dispatch {
+    Message(MidiMessage),
+    /// Switch to this profile. Sent by the window watcher.
+    SwitchProfile(String),
+    /// The connection closed.
+    Stop,
+}
+
+/// Runs the actions bound to `message` (or to a gesture that started with it).
+fn run_actions(actions: Vec<&Action>, profiles: &mut ProfileRegistry, ctx: &mut Context, message: &MidiMessage) {
+    for action in actions {
+        match action {
+            // Dedicate a button to changing profiles
+            Action::CycleProfile => {
+                let profile_name = profiles.cycle(ctx).to_string();
+                // Layers belong to the profile's bindings, so start the next one without any
+                ctx.layers.clear();
+                osd::status("Profile Changed", &format!("{} profile is now active.", profile_name));
+                //println!("Current profile: {}", profile_name); // Use if needed for debugging
+            },
+            _ => action.run(ctx, message),
+        }
+    }
+}
+
+fn device_label(device: &DeviceSettings) -> String {
+    device.name.clone().unwrap_or_else(|| match device.ports.first() {
+        Some(port) => format!("MIDI device '{}'", port),
+        None => "MIDI device".to_string(),
+    })
+}
+
+/// Connects to `device`, handles its messages and reconnects whenever it is unplugged.
+fn run_device(device: &DeviceSettings, port_selectors: &[PortSelector], mappings: Arc<Mutex<Mappings>>, claimed_ports: Arc<Mutex<HashSet<String>>>, device_events: Receiver<DeviceEvent>, services: Services) -> Result<(), Box<dyn Error>> {
     loop {
         /*###############################################################################
         Set default profile here.
+            The first profile registered in `profiles::registry()` starts out active,
+            unless the device has a "profile" in settings.json.
             Currently, the default profile is called Default
         ###############################################################################*/
-        let current_profile = Arc::new(Mutex::new(Profile::Default));
-
-        // Used for relative paths in template code.
-        let username = env::var("USERNAME").unwrap_or_else(|_| String::from("default"));
-     
-        //let mut enigo = Enigo::new(&Settings::default()).unwrap();
-        /*******************************************************************************
-        SteelSeries Audio setup
-            If you are not using SteelSeries, you can comment this part out.
-            I didn't want to run this each time I turn a knob, so I setup Sonar here.
-        *******************************************************************************/    
-        use steelseries_sonar_api::Sonar;
-        let mut sonar = Sonar::new(false,None)?;
-        
-        // button_states is used to sustain key presses. For example, in some rhythm games,
-        // you hold down a button for long notes. To accomplish this, we need to understand
-        // how our MIDI handles note-on/note-off buttons.
-        // My midi uses [144,x,y] for piano key note ons and [128,x,y] for piano key note offs.
-        // Since I am using the drum pad buttons for launching apps and things, I don't need those
-        // to sustain. 
-        let button_states: Arc<Mutex<HashMap<u8, bool>>> = Arc::new(Mutex::new(HashMap::new()));
+        let mut profiles = profiles::registry();
+        // Layer modifiers held or latched on this device (see layers.rs)
+        let mut layers = LayerStack::default();
 
         /*******************************************************************************
         MIDI input reading
@@ -74,167 +254,182 @@ fn main() -> Result<(), Box<dyn Error>> {
         let mut midi_in = MidiInput::new("midi_reader_input")?;
         midi_in.ignore(Ignore::None);
         let ports = midi_in.ports();
-        let mut enigo = Enigo::new(&Settings::default()).unwrap();
+        let mut enigo = Enigo::new(&enigo::Settings::default()).unwrap();
 
         // This checks if there is a device open at startup. If there is none, the app waits.
         if ports.is_empty() {
             println!("No MIDI input ports available. Waiting for connection...");
-            thread::sleep(Duration::from_secs(5));
+            wait_for_port_change(&device_events);
             continue; // Skip the rest of the loop and check again
         }
 
-        let in_port = &ports[0];
-        // Let user know MyMIDI is listening for input
-        midi_commands::show_toast("MyMIDI", &format!("Listening on {}",midi_in.port_name(in_port)?));
-        println!("Listening on {}", midi_in.port_name(in_port)?);
-        
-        let button_states_clone = Arc::clone(&button_states);
-        // Clone the profile Arc for use in the closure
-        let profile_for_closure = current_profile.clone();
-        
-        let mut connection = midi_in.connect(in_port, "midi_reader_input", move |_stamp, message, _| {
-            println!("Received MIDI message: {:?}", message);
-            let mut profile = profile_for_closure.lock().unwrap(); // Lock the mutex and get the profile
-            /*###############################################################################
-            Profile Change Button 
-                Dedicate a button to changing profiles
-            ###############################################################################*/
-            if message[0] == 153 && message[1] == 43 {
-                // Cycle through the profiles: Default -> Genshin -> Sky (dummy profile) -> Default
-                *profile = match *profile {
-                    Profile::Default => Profile::ZenlessZoneZero,
-                    Profile::ZenlessZoneZero => Profile::Genshin,
-                    Profile::Genshin => Profile::Default,
-                };
-                let profile_name = format!("{}", *profile); // Convert the profile to a string
-                midi_commands::show_toast("Profile Changed", &format!("{} profile is now active.", profile_name));
-                //println!("Current profile: {}", profile_name); // Use if needed for debugging
-            }
-            /*###############################################################################
-            Cross-profile Button Assignment
-                Again, button assignments defined within this main.rs function persist
-                across profiles. I have three buttons assigned as media keys for 
-                Previous Track, Play/Pause, and Next Track
-            ###############################################################################*/
-            // Go to the previous song on specific MIDI message
-            if message[0] == 153 && message[1] == 36 {
-                //n!("Playing previous song...");
-                enigo.key(Key::MediaPrevTrack,Click);
-            }
-
-            // Play/Pause the music
-            if message[0] == 153 && message[1] == 37 {
-                // Simulate play/pause key press
-                //println!("Toggling play/pause...");
-                enigo.key(Key::MediaPlayPause,Click);
-            }
-
-            // Go to the next song on specific MIDI message
-            if message[0] == 153 && message[1] == 38 {
-                //println!("Playing next song...");
-                enigo.key(Key::MediaNextTrack,Click);
-            }
-            // Launch music player
-            if message[0] == 153 && message[1] == 39 {
-                // Spotify
-                let path = format!(r"C:\\Users\\{}\\AppData\\Roaming\\Spotify\\Spotify.exe",username);
-                // Tidal
-                // let path = format!(r"C:\\Users\\{}\\AppData\\Local\\TIDAL\\TIDAL.exe",username);
-                // Note that setting path like this does NOT return an object of type &str. It returns a string, so we add &path when passing it to launch_exe
-                // If we had instead done this:
-                // let path = "your/path/here"
-                // without using format, you wouldn't need to add an & before passing it to launch_exe
-                midi_commands::launch_exe(&path);
-            }
-            /*###############################################################################
-            Volume Control
-                If you don't want to use SteelSeries Sonar, you'll have to adjust or delete this.
-                Note that you can define volume control at the profile level by including it 
-                in the profile's `handle_message` function. This would allow you to reassign knobs
-                across different profiles. 
-            ###############################################################################*/
-            if message[0] == 176 && message[1] == 70 {
-                let midi_volume = message[2] as f32 / 127.0; // Convert MIDI volume to a float in range 0.0 to 1.0
-                
-                // Using Sonar:
-                sonar.set_volume_for_channel("master",midi_volume);
-
-                // Using Windows:
-                //windows_volume_control::set_system_volume(midi_volume);
-            }
-
-            // Game Channel Volume
-            // If you don't want to use SteelSeries Sonar, you'll have to adjust or delete this:
-            if message[0] == 176 && message[1] == 71 {
-                let midi_volume = message[2] as f32 / 127.0; // Convert MIDI volume to a float in range 0.0 to 1.0
-                sonar.set_volume_for_channel("game",midi_volume);
-
-                // Using Windows:
-                //windows_volume_control::set_system_volume(midi_volume);
+        // Pick the port from the device's `ports` setting or `--port` flags (see port_select.rs)
+        let port_names: Vec<String> = ports.iter().map(|port| midi_in.port_name(port).unwrap_or_default()).collect();
+        let in_port_index = {
+            let mut claimed = claimed_ports.lock().unwrap();
+            match port_select::select_port(port_selectors, &port_names, &claimed) {
+                Some(index) => {
+                    claimed.insert(port_names[index].clone());
+                    index
+                },
+                None => {
+                    drop(claimed);
+                    println!("No free MIDI input port matches {:?} for {}. Available ports: {:?}. Waiting for connection...", device.ports, device_label(device), port_names);
+                    wait_for_port_change(&device_events);
+                    continue;
+                },
             }
+        };
+        let in_port = &ports[in_port_index];
+        let in_port_name = port_names[in_port_index].clone();
+        let source = MidiSource {
+            device: device.name.clone().unwrap_or_else(|| in_port_name.clone()),
+            port: in_port_name.clone(),
+        };
+        // Let user know MyMIDI is listening for input
+        midi_commands::show_toast("MyMIDI", &format!("Listening on {}",in_port_name));
+        println!("Listening on {}", in_port_name);
 
-            // Chat Channel Volume
-            // If you don't want to use SteelSeries Sonar, you'll have to adjust or delete this:
-            if message[0] == 176 && message[1] == 72 {
-                let midi_volume = message[2] as f32 / 127.0; // Convert MIDI volume to a float in range 0.0 to 1.0
-                sonar.set_volume_for_channel("chatRender",midi_volume);
-
-                // Using Windows:
-                //windows_volume_control::set_system_volume(midi_volume);
+        if let Some(profile_name) = &device.profile {
+            let mut ctx = services.context(&mut enigo, &mut layers);
+            if !profiles.activate(profile_name, &mut ctx) {
+                println!("Unknown profile '{}' for {}, using {}.", profile_name, source.device, profiles.active_name());
             }
+        }
 
-            // Media Channel Volume
-            // If you don't want to use SteelSeries Sonar, you'll have to adjust this:
-            if message[0] == 176 && message[1] == 73 {
-                let midi_volume = message[2] as f32 / 127.0; // Convert MIDI volume to a float in range 0.0 to 1.0
-                sonar.set_volume_for_channel("media",midi_volume);
-
-                // Using Windows:
-                //windows_volume_control::set_system_volume(midi_volume);
-            }
+        /*******************************************************************************
+        Message handling
+            The MIDI callback only decodes the message and passes it on, so it is
+            always ready for the next one. The dispatcher thread below handles
+            messages in the order they arrived, along with profile switches from the
+            window watcher. It stops when the connection closes.
+        *******************************************************************************/
+        let (message_tx, message_rx) = mpsc::channel::<Dispatch>();
+        let dispatch_tx = message_tx.clone();
+        let source_for_closure = source.clone();
+        let connection = midi_in.connect(in_port, "midi_reader_input", move |_stamp, bytes, _| {
+            let message = match MidiMessage::parse(bytes) {
+                // Clock and active sensing arrive many times a second and nothing uses them.
+                Some(MidiMessage::Realtime(_)) => return,
+                Some(message) => message,
+                None => {
+                    println!("Ignoring malformed MIDI message: {:?}", bytes);
+                    return;
+                },
+            };
+            println!("Received MIDI message from {}: {:?} {:?}", source_for_closure.device, bytes, message);
+            let _ = message_tx.send(Dispatch::Message(message));
+        }, ());
+        let dispatcher = {
+            let mappings = mappings.clone();
+            let source = source.clone();
+            let services = services.clone();
+            thread::spawn(move || {
+                // Long presses and held back taps are due without a new message, so
+                // only wait for one until the next of them (see gestures.rs).
+                let mut gestures = GestureRecognizer::new(&services.gestures);
+                loop {
+                    let received = match gestures.next_deadline() {
+                        Some(deadline) => message_rx.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                        None => message_rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    };
+                    let message = match received {
+                        Ok(Dispatch::Message(message)) => Some(message),
+                        Ok(Dispatch::SwitchProfile(profile_name)) => {
+                            if profile_name != profiles.active_name() {
+                                let mut ctx = services.context(&mut enigo, &mut layers);
+                                if profiles.activate(&profile_name, &mut ctx) {
+                                    ctx.layers.clear();
+                                } else {
+                                    println!("Unknown profile '{}' in the auto_profile rules.", profile_name);
+                                }
+                            }
+                            continue;
+                        },
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Ok(Dispatch::Stop) | Err(RecvTimeoutError::Disconnected) => break,
+                    };
+                    let mut ctx = services.context(&mut enigo, &mut layers);
+                    let profile_name = profiles.active_name().to_string();
+                    let mappings = mappings.lock().unwrap();
+                    let mut events = gestures.poll(Instant::now());
+                    // A freshly recorded macro goes on the next pad hit (see macro_recorder.rs).
+                    // That hit does nothing else, but gestures that just came due still run.
+                    let message = message.filter(|message| {
+                        let record_pad = mappings.actions_for(&profile_name, ctx.layers, &source, message).iter().any(|action| matches!(action, Action::RecordMacro));
+                        !ctx.recorder.bind_to_pad(message, &profile_name, record_pad)
+                    });
+                    if let Some(message) = message {
+                        // Letting go of a momentary layer pad turns its layer off
+                        ctx.layers.release(&message);
+                        /*###############################################################################
+                        Mapped Button Assignments
+                            Buttons and knobs are bound in the JSON files in the mappings directory
+                            (see mappings.rs). Bindings without a profile persist across profiles,
+                            like the profile change button, the media keys, Spotify and the volume knobs.
+                        ###############################################################################*/
+                        let actions = mappings.actions_for(&profile_name, ctx.layers, &source, &message);
+                        run_actions(actions, &mut profiles, &mut ctx, &message);
+                        let bound = mappings.gestures_for(&profile_name, ctx.layers, &source, &message);
+                        events.extend(gestures.handle(&message, &bound, Instant::now()));
 
-            /*###############################################################################
-            Profile Delegation
-                Adding a new profile requires a few steps.
-                    1. Create `src/profiles/profile_name.rs` with your logic to handle messages.
-                    2. Add `pub mod profile_name;` to `src/profiles/mod.rs` so you can import it
-                    into main.rs
-                    3. Add to enum list near the top of main.rs.
-                    4. Map the profile name to the handle_message function in this next section.
-            ###############################################################################*/
-            // Delegate to the appropriate profile's message handler
-            match *profile {
-                Profile::Default => profiles::default::handle_message(message),
-                Profile::Genshin => profiles::genshin::handle_message(&mut enigo, &mut button_states_clone.lock().unwrap(), message),
-                Profile::ZenlessZoneZero => profiles::zenless_zone_zero::handle_message(&mut enigo, &mut button_states_clone.lock().unwrap(), message),
-                _ => {},
-            }
-        }, ())?;
+                        // Delegate to the active profile's message handler
+                        profiles.handle(&mut ctx, &message);
+                    }
+                    for event in events {
+                        let actions = mappings.gesture_actions_for(&profile_name, ctx.layers, &source, &event);
+                        run_actions(actions, &mut profiles, &mut ctx, &event.press);
+                    }
+                }
+            })
+        };
+        let connection = match connection {
+            Ok(connection) => connection,
+            Err(e) => {
+                // Another app may have the port open. Keep going, so the device still
+                // comes back once it's free, or unplugged and plugged back in.
+                println!("Could not connect to {}: {}. Waiting for it to come back...", in_port_name, e);
+                let _ = dispatch_tx.send(Dispatch::Stop);
+                let _ = dispatcher.join();
+                claimed_ports.lock().unwrap().remove(&in_port_name);
+                wait_for_port_change(&device_events);
+                continue;
+            },
+        };
+        let switches = dispatch_tx.clone();
+        window_watcher::subscribe(move |profile_name| switches.send(Dispatch::SwitchProfile(profile_name.to_string())).is_ok());
 
-        println!("Connected. Monitoring for disconnection...");
+        println!("Connected to {}. Monitoring for disconnection...", source.device);
         /*******************************************************************************
-        Scheduled Tasks
-            This section includes code for routine maintenance. For example, there is code
-            that monitors whether the MIDI is disconnected, and if it is, it closes the port
-            and the app waits for another MIDI device to connect.
+        Disconnection
+            Wait for the device watcher to report that our port went away, then close
+            the port and wait for the device to come back.
         *******************************************************************************/
-        // Monitor connection in a block to allow for connection closure
-        {
-            let midi_in_check = MidiInput::new("midi_checker")?;
-            let mut is_connected = true;
-            while is_connected {
-                thread::sleep(Duration::from_secs(1)); // Adjust based on desired responsiveness
-
-                if midi_in_check.ports().is_empty() || !midi_in_check.ports().contains(in_port) {
-                    println!("MIDI device disconnected.");
-                    midi_commands::show_toast("MyMIDI", "MIDI disconnected. Standing by.");
-                    is_connected = false; // Exit the monitoring loop
-                }
+        loop {
+            match device_events.recv() {
+                Ok(DeviceEvent::Disconnected(name)) if name == in_port_name => break,
+                Ok(_) => {},
+                Err(_) => return Err("the device watcher stopped".into()),
             }
-        } // `midi_in_check` goes out of scope and is dropped here
+        }
+        println!("MIDI device {} disconnected.", source.device);
+        midi_commands::show_warning("MyMIDI", &format!("{} disconnected. Standing by.", source.device), Some(&format!("disconnected:{}", source.device)));
 
-        // Properly close the connection before attempting to reconnect
+        // Properly close the connection before attempting to reconnect,
+        // then let the dispatcher finish up.
         connection.close();
+        let _ = dispatch_tx.send(Dispatch::Stop);
+        let _ = dispatcher.join();
+        claimed_ports.lock().unwrap().remove(&in_port_name);
         println!("Attempting to reconnect...");
     }
-}
\ No newline at end of file
+}
+
+/// Blocks until a port appears or disappears. Also gives up after a few seconds,
+/// since a port freed by another device of ours doesn't produce an event.
+fn wait_for_port_change(device_events: &Receiver<DeviceEvent>) {
+    if device_events.recv_timeout(Duration::from_secs(5)).is_ok() {
+        // A device often brings several ports at once. Look at them all in one go.
+        device_events.try_iter().for_each(drop);
+    }
+}
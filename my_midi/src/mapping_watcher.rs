@@ -0,0 +1,65 @@
+// mapping_watcher.rs
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use crate::mappings::Mappings;
+use crate::midi_commands;
+/*###############################################################################
+Mapping Hot-Reload
+    Watches the mappings directory and swaps in the new bindings whenever a
+    mapping file is saved, so you can remap buttons while MyMIDI is running.
+    If a file doesn't parse, the previous bindings stay active and a toast shows
+    which file is broken and where.
+###############################################################################*/
+// Editors often write a file in several steps. Wait this long after the last
+// change before reloading so we only reload once per save.
+const SETTLE_TIME: Duration = Duration::from_millis(250);
+
+/// Starts watching `dir`. Keep the returned watcher alive for as long as reloads should happen.
+pub fn watch(dir: PathBuf, mappings: Arc<Mutex<Mappings>>) -> notify::Result<RecommendedWatcher> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+        match res {
+            Ok(event) => {
+                if is_mapping_change(&event) {
+                    let _ = tx.send(());
+                }
+            },
+            Err(e) => println!("Mapping watcher error: {}", e),
+        }
+    })?;
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+
+    thread::spawn(move || {
+        while rx.recv().is_ok() {
+            // Swallow the rest of the burst before reloading
+            while rx.recv_timeout(SETTLE_TIME).is_ok() {}
+            reload(&dir, &mappings);
+        }
+    });
+
+    Ok(watcher)
+}
+
+fn is_mapping_change(event: &Event) -> bool {
+    let relevant_kind = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_));
+    relevant_kind && event.paths.iter().any(|path| path.extension().is_some_and(|ext| ext == "json"))
+}
+
+fn reload(dir: &Path, mappings: &Arc<Mutex<Mappings>>) {
+    match Mappings::load(dir) {
+        Ok(new_mappings) => {
+            let count = new_mappings.binding_count();
+            *mappings.lock().unwrap() = new_mappings;
+            println!("Reloaded {} bindings from {}", count, dir.display());
+            midi_commands::show_toast("Mappings Reloaded", &format!("Loaded {} bindings.", count));
+        },
+        Err(e) => {
+            println!("Failed to reload mappings, keeping the previous ones: {}", e);
+            midi_commands::show_warning("Mappings Not Reloaded", &format!("{}", e), None);
+        },
+    }
+}
@@ -0,0 +1,450 @@
+// mappings.rs
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use serde::Deserialize;
+use enigo::{
+    Direction::Click,
+    Key, Keyboard,
+};
+use crate::gestures::{Gesture, GestureEvent};
+use crate::layers::{LayerMode, LayerStack};
+use crate::macros::Step;
+use crate::midi_commands;
+use crate::midi_message::{MidiMessage, MidiSource};
+use crate::osd;
+use crate::profiles::Context;
+use crate::settings;
+use crate::window_watcher;
+use crate::steelseries_sonar_api::{Mix, Sonar};
+/*###############################################################################
+Mapping Files
+    Button assignments live in JSON files inside the mappings directory instead of
+    being hard-coded, so moving Spotify to another pad doesn't need a recompile.
+    The directory defaults to the `mappings` folder next to the executable, or
+    in a folder above it (`my_midi/mappings` for a cargo build). Set the
+    MYMIDI_MAPPINGS environment variable to use a different one.
+
+    Every `.json` file in that directory is loaded. A file looks like this:
+    {
+        "profile": "Genshin",
+        "bindings": [
+            {
+                "trigger": { "status": 153, "note": 41 },
+                "action": { "type": "open_url", "url": "https://www.hoyolab.com/" }
+            }
+        ]
+    }
+    Leave out "profile" and the bindings work across every profile.
+
+    Triggers
+        status   The first number of the message, as printed by midi_key_mapper.
+                 153 is a drum pad press on channel 10, 176 is a knob (control change).
+                 A note on with velocity 0 counts as a note off (128-143).
+        channel  Optional, 1-16. When set, only the message type part of `status`
+                 is used (e.g. 144 + "channel": 2 matches note ons on channel 2).
+        note     Optional. The second number: note for pads/keys, controller for knobs.
+                 `cc` works as an alias.
+        value    Optional [min, max] range the third number (velocity/knob value)
+                 has to fall in. `velocity` works as an alias. Use it to tell a
+                 soft hit ([1, 63]) from a hard one ([64, 127]).
+        device   Optional. Only match messages from this device: its name in
+                 settings.json, or part of its port name.
+        gesture  Optional. "tap", "double_tap", "long_press" or "hold_repeat" fires
+                 on that gesture instead of on every press. See gestures.rs.
+        layer    Optional. Only fires while this layer is on, in place of whatever
+                 the pad does without it. See layers.rs.
+
+    Actions
+        { "type": "key_click", "key": "MediaPlayPause" }
+        { "type": "key_click", "key": { "Unicode": "p" } }
+        { "type": "launch_exe", "path": "C:\\Users\\{username}\\AppData\\Roaming\\Spotify\\Spotify.exe" }
+        { "type": "open_url", "url": "https://www.hoyolab.com/" }
+        { "type": "volume", "channel": "master" }
+        { "type": "volume", "channel": "game", "mix": "streaming" }
+        { "type": "mute", "channel": "chatRender" }
+        { "type": "sonar_chat_mix" }
+        { "type": "sonar_route", "channel": "game", "device": "Speakers" }
+        { "type": "macro", "steps": [{ "key_press": "Alt" }, { "key_click": { "Unicode": "p" } }, { "key_release": "Alt" }] }
+        { "type": "record_macro" }
+        { "type": "cycle_profile" }
+        { "type": "toggle_auto_profile" }
+        { "type": "layer", "layer": "shift" }
+        { "type": "cycle_layers", "layers": ["lows", "highs"] }
+    `{username}` in a path is replaced with the current Windows user name.
+    `volume` and `mute` work with whichever volume backend settings.json picks, and
+    `channel` is a logical channel name (see volume/mod.rs). `sonar_volume` and
+    `sonar_mute` are the old names and still work. `mute` toggles on a pad press.
+    With the OSD on (see osd.rs), a volume knob shows the channel's new level.
+    `mix` is "monitoring" (the default) or "streaming" and only matters when Sonar
+    is in Streamer Mode. The rest only work with Sonar. `sonar_chat_mix`
+    turns a knob into the game/chat balance: all the way left is all game.
+    `sonar_route` sends a channel to another output (or input, for chatCapture).
+    `device` is part of the device's name or its id; run `my_midi --list-sonar-devices`
+    to see them. In Streamer Mode the whole mix is routed.
+    `macro` plays a list of steps (keys, text, the mouse, pauses, ...); see macros.rs
+    for the steps. Hitting the pad again while it plays cancels it.
+    `record_macro` records one and binds it to the next pad you hit (see macro_recorder.rs).
+    `toggle_auto_profile` pauses and resumes switching profiles by the focused
+    window (see window_watcher.rs).
+    `layer` and `cycle_layers` turn the pad into a layer modifier (see layers.rs).
+###############################################################################*/
+#[derive(Debug, Deserialize)]
+pub struct MappingFile {
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub bindings: Vec<Binding>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Binding {
+    pub trigger: Trigger,
+    pub action: Action,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Trigger {
+    pub status: u8,
+    #[serde(default)]
+    pub channel: Option<u8>,
+    #[serde(default, alias = "cc")]
+    pub note: Option<u8>,
+    #[serde(default, alias = "velocity")]
+    pub value: Option<(u8, u8)>,
+    #[serde(default)]
+    pub device: Option<String>,
+    #[serde(default)]
+    pub gesture: Option<Gesture>,
+    #[serde(default)]
+    pub layer: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Action {
+    KeyClick { key: Key },
+    LaunchExe { path: String },
+    OpenUrl { url: String },
+    #[serde(alias = "sonar_volume")]
+    Volume {
+        channel: String,
+        #[serde(default)]
+        mix: Mix,
+    },
+    #[serde(alias = "sonar_mute")]
+    Mute {
+        channel: String,
+        #[serde(default)]
+        mix: Mix,
+    },
+    SonarChatMix,
+    SonarRoute {
+        channel: String,
+        device: String,
+        #[serde(default)]
+        mix: Mix,
+    },
+    Macro { steps: Vec<Step> },
+    RecordMacro,
+    CycleProfile,
+    ToggleAutoProfile,
+    Layer {
+        layer: String,
+        #[serde(default)]
+        mode: LayerMode,
+    },
+    CycleLayers { layers: Vec<String> },
+}
+
+/// Every mapping file found in the mappings directory.
+#[derive(Debug, Default)]
+pub struct Mappings {
+    files: Vec<MappingFile>,
+}
+
+/// The directory mapping files are loaded from.
+pub fn mappings_dir() -> PathBuf {
+    env::var_os("MYMIDI_MAPPINGS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| settings::app_path("mappings"))
+}
+
+impl Mappings {
+    pub fn load(dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                paths.push(path);
+            }
+        }
+        // Load in name order so bindings always fire in the same order.
+        paths.sort();
+
+        let mut files = Vec::new();
+        for path in paths {
+            let contents = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            let file: MappingFile = serde_json::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+            files.push(file);
+        }
+        Ok(Mappings { files })
+    }
+
+    pub fn binding_count(&self) -> usize {
+        self.files.iter().map(|file| file.bindings.len()).sum()
+    }
+
+    /// Actions bound to `message` from `source` that apply while `profile` and `layers` are active.
+    pub fn actions_for(&self, profile: &str, layers: &LayerStack, source: &MidiSource, message: &MidiMessage) -> Vec<&Action> {
+        self.pad_bindings(profile, layers, source, message)
+            .into_iter()
+            .filter(|binding| binding.trigger.gesture.is_none())
+            .map(|binding| &binding.action)
+            .collect()
+    }
+
+    /// The gestures bound to the pad that sent `message`, while `profile` and `layers` are active.
+    pub fn gestures_for(&self, profile: &str, layers: &LayerStack, source: &MidiSource, message: &MidiMessage) -> Vec<Gesture> {
+        let mut gestures = Vec::new();
+        for binding in self.pad_bindings(profile, layers, source, message) {
+            match binding.trigger.gesture {
+                Some(gesture) if !gestures.contains(&gesture) => gestures.push(gesture),
+                _ => {},
+            }
+        }
+        gestures
+    }
+
+    /// Actions bound to `event` from `source` that apply while `profile` and `layers` are active.
+    pub fn gesture_actions_for(&self, profile: &str, layers: &LayerStack, source: &MidiSource, event: &GestureEvent) -> Vec<&Action> {
+        self.pad_bindings(profile, layers, source, &event.press)
+            .into_iter()
+            .filter(|binding| binding.trigger.gesture == Some(event.gesture))
+            .map(|binding| &binding.action)
+            .collect()
+    }
+
+    /// The bindings for the pad that sent `message`, from the topmost active layer
+    /// that binds it, or the bindings without a layer if none does.
+    fn pad_bindings(&self, profile: &str, layers: &LayerStack, source: &MidiSource, message: &MidiMessage) -> Vec<&Binding> {
+        let on_pad: Vec<&Binding> = self.files
+            .iter()
+            .filter(|file| file.profile.is_none() || file.profile.as_deref() == Some(profile))
+            .flat_map(|file| file.bindings.iter())
+            .filter(|binding| binding.trigger.matches(source, message))
+            .collect();
+        let layer = layers.active().into_iter().find(|layer| on_pad.iter().any(|binding| binding.trigger.layer.as_deref() == Some(*layer)));
+        on_pad.into_iter().filter(|binding| binding.trigger.layer.as_deref() == layer).collect()
+    }
+}
+
+impl Trigger {
+    /// Whether `message` comes from the pad, knob or key the trigger is about.
+    pub fn matches(&self, source: &MidiSource, message: &MidiMessage) -> bool {
+        if let Some(device) = &self.device {
+            let same_device = source.device == *device || source.port.to_lowercase().contains(&device.to_lowercase());
+            if !same_device {
+                return false;
+            }
+        }
+        let Some(status) = message.status() else {
+            return false;
+        };
+        let status_matches = match self.channel {
+            Some(channel) => status & 0xF0 == self.status & 0xF0 && message.channel() == Some(channel),
+            None => status == self.status,
+        };
+        if !status_matches {
+            return false;
+        }
+        if let Some(note) = self.note {
+            if message.note() != Some(note) {
+                return false;
+            }
+        }
+        if let Some((min, max)) = self.value {
+            match message.value() {
+                Some(value) if (min..=max).contains(&value) => {},
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+impl Action {
+    /// Runs the action. Key clicks happen right away on the calling thread. Anything
+    /// that can block (launching apps, the browser, Sonar requests) goes to the executor,
+    /// and macros to the macro runner.
+    /// `cycle_profile` is handled by the caller since it owns the profiles.
+    pub fn run(&self, ctx: &mut Context, message: &MidiMessage) {
+        match self {
+            Action::KeyClick { key } => {
+                if let Err(e) = ctx.enigo.key(*key, Click) {
+                    println!("Failed to press {:?}: {}", key, e);
+                }
+            },
+            Action::LaunchExe { path } => {
+                let username = env::var("USERNAME").unwrap_or_else(|_| String::from("default"));
+                let path = path.replace("{username}", &username);
+                ctx.executor.spawn(move || midi_commands::launch_exe(&path));
+            },
+            Action::OpenUrl { url } => {
+                let url = url.clone();
+                ctx.executor.spawn(move || {
+                    let _ = midi_commands::open_url(&url);
+                });
+            },
+            Action::Volume { channel, mix } => {
+                // Convert MIDI volume to a float in range 0.0 to 1.0
+                let midi_volume = message.value().unwrap_or(0) as f32 / 127.0;
+                let (volume, channel, mix) = (ctx.volume.clone(), channel.clone(), *mix);
+                // Knobs send a burst of values; the throttle only sends the newest ones
+                ctx.throttle.run_latest(&format!("volume:{}:{}", mix, channel), move || {
+                    match volume.set_volume(&channel, mix, midi_volume) {
+                        Ok(()) if mix == Mix::Streaming => osd::level(&format!("{} (streaming)", channel), midi_volume),
+                        Ok(()) => osd::level(&channel, midi_volume),
+                        Err(e) => midi_commands::show_error("Volume Control Failed", &format!("{}: {}", volume.backend_name(), e)),
+                    }
+                });
+            },
+            Action::Mute { channel, mix } => {
+                let (volume, channel, mix) = (ctx.volume.clone(), channel.clone(), *mix);
+                ctx.executor.spawn(move || {
+                    match volume.toggle_mute(&channel, mix) {
+                        Ok(true) => midi_commands::show_toast("MyMIDI", &format!("{} muted", channel)),
+                        Ok(false) => midi_commands::show_toast("MyMIDI", &format!("{} unmuted", channel)),
+                        Err(e) => midi_commands::show_error("Mute Failed", &format!("{}: {}", volume.backend_name(), e)),
+                    }
+                });
+            },
+            Action::SonarChatMix => {
+                // 0 is all game, 127 is all chat
+                let balance = message.value().unwrap_or(64) as f32 / 127.0 * 2.0 - 1.0;
+                let Some(sonar) = sonar_or_complain(ctx) else { return };
+                ctx.throttle.run_latest("chat_mix", move || {
+                    if let Err(e) = sonar.lock().unwrap().set_chat_mix(balance) {
+                        midi_commands::show_error("Chat Mix Failed", &e.to_string());
+                    }
+                });
+            },
+            Action::SonarRoute { channel, device, mix } => {
+                let Some(sonar) = sonar_or_complain(ctx) else { return };
+                let (channel, device, mix) = (channel.clone(), device.clone(), *mix);
+                ctx.executor.spawn(move || {
+                    let result = sonar.lock().unwrap().route_channel_to(&channel, mix, &device);
+                    match result {
+                        Ok(device) => midi_commands::show_toast("Sonar", &format!("{} now plays on {}", channel, device.name)),
+                        Err(e) => midi_commands::show_error("Routing Failed", &e.to_string()),
+                    }
+                });
+            },
+            Action::Macro { steps } => {
+                // The pad that started a macro is the one that cancels it
+                let pad = format!("{}:{}", message.status().unwrap_or(0), message.note().unwrap_or(0));
+                ctx.macros.toggle(&pad, steps);
+            },
+            Action::RecordMacro => ctx.recorder.toggle(),
+            Action::CycleProfile => {},
+            Action::ToggleAutoProfile => {
+                match window_watcher::toggle_paused() {
+                    Some(true) => osd::status("Automatic Profiles", "Paused. Profiles only change with the profile pad."),
+                    Some(false) => osd::status("Automatic Profiles", "Switching profiles by window again."),
+                    None => println!("toggle_auto_profile needs auto_profile rules in settings.json."),
+                }
+            },
+            Action::Layer { layer, mode: LayerMode::Momentary } => ctx.layers.hold(layer, message),
+            Action::Layer { layer, mode: LayerMode::Latch } => {
+                match ctx.layers.toggle(layer) {
+                    true => osd::status("Layer Changed", &format!("{} is on.", layer)),
+                    false => osd::status("Layer Changed", &format!("{} is off.", layer)),
+                }
+            },
+            Action::CycleLayers { layers } => {
+                match ctx.layers.cycle(layers) {
+                    Some(layer) => osd::status("Layer Changed", &format!("{} is on.", layer)),
+                    None => osd::status("Layer Changed", "Back to the base layer."),
+                }
+            },
+        }
+    }
+}
+
+/// The shared Sonar, or None (with a message) if another volume backend is in use.
+fn sonar_or_complain(ctx: &Context) -> Option<Arc<Mutex<Sonar>>> {
+    if ctx.sonar.is_none() {
+        println!("Sonar bindings need \"backend\": \"sonar\" in the volume settings.");
+    }
+    ctx.sonar.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mappings(json: &str) -> Mappings {
+        Mappings { files: vec![serde_json::from_str(json).unwrap()] }
+    }
+
+    fn urls(actions: Vec<&Action>) -> Vec<&str> {
+        actions.into_iter().filter_map(|action| match action {
+            Action::OpenUrl { url } => Some(url.as_str()),
+            _ => None,
+        }).collect()
+    }
+
+    const LAYERED: &str = r#"{ "bindings": [
+        { "trigger": { "status": 153, "note": 36 }, "action": { "type": "open_url", "url": "base 36" } },
+        { "trigger": { "status": 153, "note": 37 }, "action": { "type": "open_url", "url": "base 37" } },
+        { "trigger": { "status": 153, "note": 36, "layer": "shift" }, "action": { "type": "open_url", "url": "shift 36" } },
+        { "trigger": { "status": 153, "note": 36, "layer": "fn" }, "action": { "type": "open_url", "url": "fn 36" } }
+    ] }"#;
+
+    #[test]
+    fn the_topmost_layer_binding_the_pad_wins() {
+        let mappings = mappings(LAYERED);
+        let source = MidiSource { device: String::from("MPK"), port: String::from("MPK Mini Play") };
+        let pad = |note| MidiMessage::NoteOn { channel: 10, note, velocity: 100 };
+        let mut layers = LayerStack::default();
+        assert_eq!(urls(mappings.actions_for("Default", &layers, &source, &pad(36))), vec!["base 36"]);
+        layers.toggle("fn");
+        layers.toggle("shift");
+        assert_eq!(urls(mappings.actions_for("Default", &layers, &source, &pad(36))), vec!["shift 36"]);
+        // The layers don't bind 37, so it falls through to the base layer
+        assert_eq!(urls(mappings.actions_for("Default", &layers, &source, &pad(37))), vec!["base 37"]);
+        layers.toggle("shift");
+        assert_eq!(urls(mappings.actions_for("Default", &layers, &source, &pad(36))), vec!["fn 36"]);
+    }
+
+    fn trigger(json: &str) -> Trigger {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn triggers_match_status_channel_note_and_value() {
+        let source = MidiSource { device: String::from("pads"), port: String::from("MPK Mini Play 0") };
+        let pad = |channel, note, velocity| MidiMessage::NoteOn { channel, note, velocity };
+        let cases = [
+            (r#"{ "status": 153 }"#, pad(10, 36, 100), true),
+            (r#"{ "status": 153 }"#, pad(9, 36, 100), false),
+            (r#"{ "status": 153 }"#, MidiMessage::NoteOff { channel: 10, note: 36, velocity: 0 }, false),
+            (r#"{ "status": 144, "channel": 2 }"#, pad(2, 36, 100), true),
+            (r#"{ "status": 144, "channel": 2 }"#, pad(3, 36, 100), false),
+            (r#"{ "status": 153, "note": 36 }"#, pad(10, 36, 100), true),
+            (r#"{ "status": 153, "note": 36 }"#, pad(10, 37, 100), false),
+            (r#"{ "status": 176, "cc": 70 }"#, MidiMessage::ControlChange { channel: 1, controller: 70, value: 5 }, true),
+            (r#"{ "status": 153, "value": [64, 127] }"#, pad(10, 36, 64), true),
+            (r#"{ "status": 153, "velocity": [64, 127] }"#, pad(10, 36, 63), false),
+            (r#"{ "status": 153, "device": "pads" }"#, pad(10, 36, 100), true),
+            (r#"{ "status": 153, "device": "mpk mini" }"#, pad(10, 36, 100), true),
+            (r#"{ "status": 153, "device": "knobs" }"#, pad(10, 36, 100), false),
+            (r#"{ "status": 248 }"#, MidiMessage::Realtime(crate::midi_message::Realtime::TimingClock), false),
+        ];
+        for (json, message, expected) in cases {
+            assert_eq!(trigger(json).matches(&source, &message), expected, "{} with {:?}", json, message);
+        }
+    }
+}
@@ -1,12 +1,13 @@
 use std::process::Command;
 use webbrowser;
+use crate::notifier::{self, Notification, Severity};
 
 pub fn launch_exe(path: &str) {
     match Command::new(path).spawn() {
         Ok(_) => println!("Launched successfully."),
         Err(e) => {
             println!("Failed to launch: {}", e);
-            show_toast("Launch Failed", &format!("Failed to launch: {}", e));
+            show_error("Launch Failed", &format!("Failed to launch: {}", e));
         },
     }
 }
@@ -17,28 +18,27 @@ pub fn open_url(url: &str) -> Result<(),webbrowser::ParseBrowserError> {
     Ok(())
 }
 
+/// Shows a toast with whichever notifier settings.json picked (see notifier/mod.rs).
+/// It is shown in the background, so this returns right away. The text is passed
+/// on safely, so it can contain anything.
 pub fn show_toast(title: &str, message: &str) {
-    let ps_script = format!(r#"
-[Windows.UI.Notifications.ToastNotificationManager, Windows.UI.Notifications, ContentType = WindowsRuntime] > $null
-$template = [Windows.UI.Notifications.ToastNotificationManager]::GetTemplateContent([Windows.UI.Notifications.ToastTemplateType]::ToastText02)
-
-$textNodes = $template.GetElementsByTagName("text")
-$textNodes.Item(0).AppendChild($template.CreateTextNode("{title}")) > $null
-$textNodes.Item(1).AppendChild($template.CreateTextNode("{message}")) > $null
+    notifier::notify(Notification::new(Severity::Info, title, message));
+}
 
-$toast = [Windows.UI.Notifications.ToastNotification]::new($template)
-$notifier = [Windows.UI.Notifications.ToastNotificationManager]::CreateToastNotifier("MyMIDI")
-$notifier.Show($toast)
-"#, title = title, message = message);
+/// Like `show_toast`, for something that went wrong but MyMIDI keeps going.
+/// Warnings with the same `key` (or the same title, without one) are merged
+/// when they come in quick succession.
+pub fn show_warning(title: &str, message: &str, key: Option<&str>) {
+    let notification = Notification::new(Severity::Warning, title, message);
+    notifier::notify(match key {
+        Some(key) => notification.with_key(key),
+        None => notification,
+    });
+}
 
-    Command::new("powershell")
-        .arg("-NoProfile")
-        .arg("-ExecutionPolicy")
-        .arg("Bypass")
-        .arg("-Command")
-        .arg(&ps_script)
-        .output()
-        .expect("Failed to execute process");
+/// Like `show_toast`, for a failed action.
+pub fn show_error(title: &str, message: &str) {
+    notifier::notify(Notification::new(Severity::Error, title, message));
 }
 
 
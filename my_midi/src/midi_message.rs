@@ -0,0 +1,234 @@
+// midi_message.rs
+/*###############################################################################
+MIDI Message Decoding
+    midir hands us raw bytes like [153,36,100]. Instead of indexing into those
+    bytes everywhere (which panics on one-byte messages such as the clock or
+    active sensing), they are decoded once into a MidiMessage.
+
+    The first byte is the status byte. Its high half is the message type and its
+    low half is the channel, so 153 (0x99) is a note on (0x90) on channel 10.
+    Channels are numbered 1-16 here, like on the device.
+
+    A note on with velocity 0 means "note off" to most devices, so it is decoded
+    as a NoteOff. Profiles only ever need to look for NoteOff to catch releases.
+###############################################################################*/
+/// Which device a message came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MidiSource {
+    /// The device's name from settings, or the port name if it has none.
+    pub device: String,
+    pub port: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MidiMessage {
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    PolyAftertouch { channel: u8, note: u8, pressure: u8 },
+    ControlChange { channel: u8, controller: u8, value: u8 },
+    ProgramChange { channel: u8, program: u8 },
+    ChannelPressure { channel: u8, pressure: u8 },
+    /// 14-bit bend amount. 8192 is the center.
+    PitchBend { channel: u8, value: u16 },
+    /// The full system exclusive message, including the leading 0xF0 and trailing 0xF7.
+    SysEx(Vec<u8>),
+    Realtime(Realtime),
+    /// System common messages (MTC quarter frame, song position, ...) we don't act on.
+    SystemCommon(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Realtime {
+    TimingClock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    Reset,
+}
+
+impl MidiMessage {
+    /// Decodes a raw message. Returns None for empty, truncated or unknown messages.
+    pub fn parse(bytes: &[u8]) -> Option<MidiMessage> {
+        let status = *bytes.first()?;
+        if status < 0x80 {
+            // Data byte without a status byte
+            return None;
+        }
+        if status >= 0xF0 {
+            return Self::parse_system(status, bytes);
+        }
+
+        let channel = (status & 0x0F) + 1;
+        let data1 = bytes.get(1).copied().filter(|b| *b < 0x80);
+        let data2 = bytes.get(2).copied().filter(|b| *b < 0x80);
+        let message = match status & 0xF0 {
+            0x80 => MidiMessage::NoteOff { channel, note: data1?, velocity: data2? },
+            0x90 => match data2? {
+                0 => MidiMessage::NoteOff { channel, note: data1?, velocity: 0 },
+                velocity => MidiMessage::NoteOn { channel, note: data1?, velocity },
+            },
+            0xA0 => MidiMessage::PolyAftertouch { channel, note: data1?, pressure: data2? },
+            0xB0 => MidiMessage::ControlChange { channel, controller: data1?, value: data2? },
+            0xC0 => MidiMessage::ProgramChange { channel, program: data1? },
+            0xD0 => MidiMessage::ChannelPressure { channel, pressure: data1? },
+            0xE0 => MidiMessage::PitchBend { channel, value: (data2? as u16) << 7 | data1? as u16 },
+            _ => unreachable!(),
+        };
+        Some(message)
+    }
+
+    fn parse_system(status: u8, bytes: &[u8]) -> Option<MidiMessage> {
+        let realtime = match status {
+            0xF8 => Some(Realtime::TimingClock),
+            0xFA => Some(Realtime::Start),
+            0xFB => Some(Realtime::Continue),
+            0xFC => Some(Realtime::Stop),
+            0xFE => Some(Realtime::ActiveSensing),
+            0xFF => Some(Realtime::Reset),
+            _ => None,
+        };
+        if let Some(realtime) = realtime {
+            return Some(MidiMessage::Realtime(realtime));
+        }
+        match status {
+            0xF0 => Some(MidiMessage::SysEx(bytes.to_vec())),
+            0xF1..=0xF6 => Some(MidiMessage::SystemCommon(bytes.to_vec())),
+            _ => None,
+        }
+    }
+
+    /// Channel 1-16 for channel messages.
+    pub fn channel(&self) -> Option<u8> {
+        match *self {
+            MidiMessage::NoteOff { channel, .. }
+            | MidiMessage::NoteOn { channel, .. }
+            | MidiMessage::PolyAftertouch { channel, .. }
+            | MidiMessage::ControlChange { channel, .. }
+            | MidiMessage::ProgramChange { channel, .. }
+            | MidiMessage::ChannelPressure { channel, .. }
+            | MidiMessage::PitchBend { channel, .. } => Some(channel),
+            _ => None,
+        }
+    }
+
+    /// The status byte as the device would send it, e.g. 153 for a note on
+    /// channel 10. A note on with velocity 0 reports the note off status.
+    pub fn status(&self) -> Option<u8> {
+        let kind = match self {
+            MidiMessage::NoteOff { .. } => 0x80,
+            MidiMessage::NoteOn { .. } => 0x90,
+            MidiMessage::PolyAftertouch { .. } => 0xA0,
+            MidiMessage::ControlChange { .. } => 0xB0,
+            MidiMessage::ProgramChange { .. } => 0xC0,
+            MidiMessage::ChannelPressure { .. } => 0xD0,
+            MidiMessage::PitchBend { .. } => 0xE0,
+            _ => return None,
+        };
+        Some(kind | (self.channel()? - 1))
+    }
+
+    /// Note number for note messages, controller number for control changes,
+    /// program number for program changes.
+    pub fn note(&self) -> Option<u8> {
+        match *self {
+            MidiMessage::NoteOff { note, .. }
+            | MidiMessage::NoteOn { note, .. }
+            | MidiMessage::PolyAftertouch { note, .. } => Some(note),
+            MidiMessage::ControlChange { controller, .. } => Some(controller),
+            MidiMessage::ProgramChange { program, .. } => Some(program),
+            _ => None,
+        }
+    }
+
+    /// Velocity, pressure or controller value, 0-127. Pitch bend is scaled down to 0-127.
+    pub fn value(&self) -> Option<u8> {
+        match *self {
+            MidiMessage::NoteOff { velocity, .. } | MidiMessage::NoteOn { velocity, .. } => Some(velocity),
+            MidiMessage::PolyAftertouch { pressure, .. } | MidiMessage::ChannelPressure { pressure, .. } => Some(pressure),
+            MidiMessage::ControlChange { value, .. } => Some(value),
+            MidiMessage::PitchBend { value, .. } => Some((value >> 7) as u8),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_channel_messages() {
+        let cases: &[(&[u8], MidiMessage)] = &[
+            (&[0x99, 36, 100], MidiMessage::NoteOn { channel: 10, note: 36, velocity: 100 }),
+            (&[0x99, 36, 0], MidiMessage::NoteOff { channel: 10, note: 36, velocity: 0 }),
+            (&[0x80, 60, 64], MidiMessage::NoteOff { channel: 1, note: 60, velocity: 64 }),
+            (&[0xAF, 60, 5], MidiMessage::PolyAftertouch { channel: 16, note: 60, pressure: 5 }),
+            (&[0xB0, 70, 127], MidiMessage::ControlChange { channel: 1, controller: 70, value: 127 }),
+            (&[0xC3, 12], MidiMessage::ProgramChange { channel: 4, program: 12 }),
+            (&[0xD1, 90], MidiMessage::ChannelPressure { channel: 2, pressure: 90 }),
+            (&[0xE0, 0x00, 0x40], MidiMessage::PitchBend { channel: 1, value: 8192 }),
+            (&[0xE0, 0x7F, 0x7F], MidiMessage::PitchBend { channel: 1, value: 16383 }),
+            (&[0xE0, 0x01, 0x00], MidiMessage::PitchBend { channel: 1, value: 1 }),
+        ];
+        for (bytes, expected) in cases {
+            assert_eq!(MidiMessage::parse(bytes).as_ref(), Some(expected), "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn channels_run_from_1_to_16() {
+        for low in 0..16u8 {
+            let message = MidiMessage::parse(&[0x90 | low, 36, 100]).unwrap();
+            assert_eq!(message.channel(), Some(low + 1));
+            assert_eq!(message.status(), Some(0x90 | low));
+        }
+    }
+
+    #[test]
+    fn decodes_system_messages() {
+        let cases: &[(&[u8], MidiMessage)] = &[
+            (&[0xF8], MidiMessage::Realtime(Realtime::TimingClock)),
+            (&[0xFA], MidiMessage::Realtime(Realtime::Start)),
+            (&[0xFC], MidiMessage::Realtime(Realtime::Stop)),
+            (&[0xFE], MidiMessage::Realtime(Realtime::ActiveSensing)),
+            (&[0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7], MidiMessage::SysEx(vec![0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7])),
+            (&[0xF2, 0x10, 0x20], MidiMessage::SystemCommon(vec![0xF2, 0x10, 0x20])),
+        ];
+        for (bytes, expected) in cases {
+            assert_eq!(MidiMessage::parse(bytes).as_ref(), Some(expected), "{:?}", bytes);
+            assert_eq!(expected.channel(), None);
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_and_running_status_messages() {
+        let cases: &[&[u8]] = &[
+            &[],
+            &[0x90],
+            &[0x90, 36],
+            &[0xB0, 70],
+            &[0xC0],
+            &[0xE0, 0x00],
+            // Running status: data bytes reusing the previous status byte
+            &[36, 100],
+            &[0x7F],
+            // A status byte where a data byte should be
+            &[0x90, 36, 0x80],
+            &[0xF9],
+        ];
+        for bytes in cases {
+            assert_eq!(MidiMessage::parse(bytes), None, "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn reports_note_and_value() {
+        let pad = MidiMessage::parse(&[0x99, 36, 100]).unwrap();
+        assert_eq!((pad.note(), pad.value()), (Some(36), Some(100)));
+        let bend = MidiMessage::parse(&[0xE0, 0x00, 0x40]).unwrap();
+        assert_eq!((bend.note(), bend.value()), (None, Some(64)));
+        let program = MidiMessage::parse(&[0xC0, 5]).unwrap();
+        assert_eq!((program.note(), program.value()), (Some(5), None));
+    }
+}
@@ -0,0 +1,168 @@
+use std::error::Error;
+use std::process::Command;
+use super::{clean, Notifier, NotifyResult};
+/*###############################################################################
+Desktop Notifications
+    Calls org.freedesktop.Notifications.Notify on the session bus with `gdbus`,
+    which ships with GLib, so there's nothing extra to install on a Linux desktop.
+    gdbus reads each argument as a GVariant, so the title and message are
+    written out as quoted GVariant strings. Every quote, backslash and control
+    character in them is escaped, so they can't turn into anything but a string.
+    The message is also escaped as markup, since servers may render <b> and &amp;.
+    `show` also takes the id of a notification to replace and extra hints, which
+    the OSD (osd.rs) uses to update one volume bar instead of stacking popups.
+###############################################################################*/
+const APP_NAME: &str = "MyMIDI";
+
+/// How long a notification stays up, in milliseconds.
+const EXPIRE_TIMEOUT_MS: i32 = 5000;
+
+/// A hint's name and its value, written as a GVariant (e.g. "<int32 42>").
+pub type Hint = (&'static str, String);
+
+pub struct DesktopNotifier {
+    program: String,
+}
+
+impl DesktopNotifier {
+    pub fn new() -> Self {
+        DesktopNotifier { program: String::from("gdbus") }
+    }
+
+    /// Shows a notification, replacing the one with id `replaces_id` if it's still
+    /// up (0 for a new one), and returns its id.
+    pub fn show(&self, title: &str, message: &str, replaces_id: u32, hints: &[Hint], timeout_ms: i32) -> Result<u32, Box<dyn Error + Send + Sync>> {
+        let output = self.command(title, message, replaces_id, hints, timeout_ms).output()
+            .map_err(|e| format!("Could not run {}: {}", self.program, e))?;
+        if !output.status.success() {
+            return Err(format!("{} failed: {}", self.program, String::from_utf8_lossy(&output.stderr).trim()).into());
+        }
+        let reply = String::from_utf8_lossy(&output.stdout);
+        parse_id(&reply).ok_or_else(|| format!("Unexpected reply from {}: {}", self.program, reply.trim()).into())
+    }
+
+    fn command(&self, title: &str, message: &str, replaces_id: u32, hints: &[Hint], timeout_ms: i32) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args([
+            "call", "--session",
+            "--dest", "org.freedesktop.Notifications",
+            "--object-path", "/org/freedesktop/Notifications",
+            "--method", "org.freedesktop.Notifications.Notify",
+        ]);
+        command.args(notify_args(title, message, replaces_id, hints, timeout_ms));
+        command
+    }
+}
+
+impl Default for DesktopNotifier {
+    fn default() -> Self {
+        DesktopNotifier::new()
+    }
+}
+
+impl Notifier for DesktopNotifier {
+    fn notify(&self, title: &str, message: &str) -> NotifyResult {
+        self.show(title, message, 0, &[], EXPIRE_TIMEOUT_MS)?;
+        Ok(())
+    }
+}
+
+/// The arguments of Notify: app name, replaces id, icon, summary, body, actions, hints, timeout.
+fn notify_args(title: &str, message: &str, replaces_id: u32, hints: &[Hint], timeout_ms: i32) -> Vec<String> {
+    let hints: Vec<String> = hints.iter().map(|(name, value)| format!("{}: {}", gvariant_string(name), value)).collect();
+    vec![
+        gvariant_string(APP_NAME),
+        replaces_id.to_string(),
+        gvariant_string(""),
+        gvariant_string(&clean(title)),
+        gvariant_string(&escape_markup(&clean(message))),
+        String::from("@as []"),
+        format!("@a{{sv}} {{{}}}", hints.join(", ")),
+        timeout_ms.to_string(),
+    ]
+}
+
+/// The notification id in gdbus' reply, which looks like "(uint32 7,)".
+fn parse_id(reply: &str) -> Option<u32> {
+    reply.trim().strip_prefix("(uint32 ")?.strip_suffix(",)")?.parse().ok()
+}
+
+/// `text` as a double quoted GVariant string literal.
+pub fn gvariant_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn escape_markup(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_gvariant_strings() {
+        assert_eq!(gvariant_string("Profile Changed"), r#""Profile Changed""#);
+        assert_eq!(gvariant_string(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(gvariant_string(r"C:\Users\"), r#""C:\\Users\\""#);
+        assert_eq!(gvariant_string("two\nlines"), r#""two\nlines""#);
+        assert_eq!(gvariant_string("it's $(rm -rf ~)"), r#""it's $(rm -rf ~)""#);
+        assert_eq!(gvariant_string("\u{1b}[31m"), r#""\u001b[31m""#);
+    }
+
+    #[test]
+    fn builds_notify_call() {
+        let args = notify_args("Launch Failed", "No such file: <C:\\a & b>", 0, &[], EXPIRE_TIMEOUT_MS);
+        assert_eq!(args, vec![
+            r#""MyMIDI""#, "0", r#""""#,
+            r#""Launch Failed""#,
+            r#""No such file: &lt;C:\\a &amp; b&gt;""#,
+            "@as []", "@a{sv} {}", "5000",
+        ]);
+    }
+
+    #[test]
+    fn replaces_with_hints() {
+        let hints = [("value", String::from("<int32 42>")), ("x-dunst-stack-tag", format!("<{}>", gvariant_string("volume:master")))];
+        let args = notify_args("master", "42%", 7, &hints, 1500);
+        assert_eq!(args[1], "7");
+        assert_eq!(args[6], r#"@a{sv} {"value": <int32 42>, "x-dunst-stack-tag": <"volume:master">}"#);
+        assert_eq!(args[7], "1500");
+    }
+
+    #[test]
+    fn reads_notification_id() {
+        assert_eq!(parse_id("(uint32 7,)\n"), Some(7));
+        assert_eq!(parse_id("(uint32 4294967295,)"), Some(u32::MAX));
+        assert_eq!(parse_id("()"), None);
+        assert_eq!(parse_id("Error: GDBus.Error"), None);
+    }
+
+    #[test]
+    fn hostile_text_stays_one_argument() {
+        let notifier = DesktopNotifier::new();
+        let plain: Vec<_> = notifier.command("Title", "Message", 0, &[], EXPIRE_TIMEOUT_MS).get_args().map(|arg| arg.to_owned()).collect();
+        let hostile = "\" @as [] \"', --session --dest evil \"\\";
+        let command = notifier.command(hostile, hostile, 0, &[], EXPIRE_TIMEOUT_MS);
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args.len(), plain.len());
+        for arg in &args[11..13] {
+            let arg = arg.to_str().unwrap();
+            assert!(arg.starts_with('"') && arg.ends_with('"'));
+            let inner = &arg[1..arg.len() - 1];
+            assert!(!inner.replace("\\\\", "").replace("\\\"", "").contains('"'), "unescaped quote in {}", arg);
+        }
+    }
+}
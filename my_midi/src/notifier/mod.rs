@@ -0,0 +1,251 @@
+use std::error::Error;
+use std::sync::OnceLock;
+use std::time::Duration;
+#[cfg(test)]
+use std::sync::Mutex;
+use serde::Deserialize;
+
+pub mod desktop;
+mod queue;
+pub mod windows;
+
+use desktop::DesktopNotifier;
+use windows::CommandNotifier;
+
+/*###############################################################################
+Notifiers
+    Toasts ("Profile Changed", "Launch Failed", ...) go through a `Notifier`, so
+    MyMIDI doesn't need PowerShell to run. Pick one in settings.json:
+        "notifications": { "backend": "desktop" }
+    Backends:
+        windows  Windows toasts through PowerShell (the default on Windows).
+        desktop  Freedesktop notifications over D-Bus, through `gdbus` (the
+                 default on Linux). Works with GNOME, KDE, dunst, mako, ...
+        log      Print them to the console (the default anywhere else).
+        none     Don't show them at all.
+    Notifications are shown in the background (see queue.rs), so sending one
+    never waits. Two more settings keep them from piling up:
+        "notifications": { "backend": "desktop", "min_severity": "warning", "dedup_seconds": 10 }
+        min_severity   "info" (the default), "warning" or "error". Anything less
+                       important is only printed to the console.
+        dedup_seconds  The same warning or error (same key, which is the title
+                       unless the sender picks one) is shown at most once in this
+                       many seconds (default 10). Use 0 to show every one. Info
+                       notifications answer something you did ("game muted"), so
+                       they are always shown.
+    Titles and messages often contain text we don't control: error messages,
+    file paths, device and profile names. Backends must never paste them into
+    a script or command line that gets interpreted.
+
+    Adding a backend:
+        1. Create `src/notifier/backend_name.rs` with a struct that implements `Notifier`.
+        2. Add `pub mod backend_name;` above, a variant to `NotifierKind` and a case in `create`.
+###############################################################################*/
+pub type NotifyResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+/// Longest title or message passed on. Toasts only show a couple of lines anyway.
+const MAX_TEXT_CHARS: usize = 500;
+
+/// How long a repeated notification is held back when `dedup_seconds` isn't set.
+const DEFAULT_DEDUP_SECONDS: f32 = 10.0;
+
+pub trait Notifier: Send + Sync {
+    fn notify(&self, title: &str, message: &str) -> NotifyResult;
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub severity: Severity,
+    pub title: String,
+    pub message: String,
+    /// Notifications with the same key count as repeats of each other.
+    pub key: String,
+}
+
+impl Notification {
+    pub fn new(severity: Severity, title: &str, message: &str) -> Self {
+        Notification { severity, title: title.to_string(), message: message.to_string(), key: title.to_string() }
+    }
+
+    pub fn with_key(mut self, key: &str) -> Self {
+        self.key = key.to_string();
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifierKind {
+    Windows,
+    Desktop,
+    Log,
+    None,
+}
+
+impl Default for NotifierKind {
+    fn default() -> Self {
+        if cfg!(windows) {
+            NotifierKind::Windows
+        } else if cfg!(target_os = "linux") {
+            NotifierKind::Desktop
+        } else {
+            NotifierKind::Log
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct NotifierSettings {
+    pub backend: NotifierKind,
+    pub min_severity: Severity,
+    pub dedup_seconds: Option<f32>,
+}
+
+impl NotifierSettings {
+    fn dedup_window(&self) -> Duration {
+        Duration::from_secs_f32(self.dedup_seconds.unwrap_or(DEFAULT_DEDUP_SECONDS).max(0.0))
+    }
+}
+
+pub fn create(kind: NotifierKind) -> Box<dyn Notifier> {
+    match kind {
+        NotifierKind::Windows => Box::new(CommandNotifier::powershell_toast()),
+        NotifierKind::Desktop => Box::new(DesktopNotifier::new()),
+        NotifierKind::Log => Box::new(LogNotifier),
+        NotifierKind::None => Box::new(Silent),
+    }
+}
+
+static NOTIFIER: OnceLock<Box<dyn Notifier>> = OnceLock::new();
+static SETTINGS: OnceLock<NotifierSettings> = OnceLock::new();
+
+/// Picks the notifier every toast goes through. Call it once, at startup.
+pub fn install(settings: &NotifierSettings) {
+    if SETTINGS.set(settings.clone()).is_err() || NOTIFIER.set(create(settings.backend)).is_err() {
+        println!("The notifier was already set up.");
+    }
+}
+
+/// Queues `notification` to be shown and returns right away. Without `install`,
+/// the platform's default notifier and settings are used.
+pub fn notify(notification: Notification) {
+    let settings = SETTINGS.get_or_init(NotifierSettings::default);
+    if notification.severity < settings.min_severity {
+        println!("[{}] {}", notification.title, notification.message);
+        return;
+    }
+    let notifier = NOTIFIER.get_or_init(|| create(settings.backend)).as_ref();
+    queue::send(notification, notifier, settings.dedup_window());
+}
+
+/// Prints notifications to the console.
+pub struct LogNotifier;
+
+impl Notifier for LogNotifier {
+    fn notify(&self, title: &str, message: &str) -> NotifyResult {
+        println!("[{}] {}", clean(title), clean(message));
+        Ok(())
+    }
+}
+
+/// Drops every notification.
+struct Silent;
+
+impl Notifier for Silent {
+    fn notify(&self, _title: &str, _message: &str) -> NotifyResult {
+        Ok(())
+    }
+}
+
+/// Keeps every notification so tests can check what would have been shown.
+#[cfg(test)]
+#[derive(Default)]
+pub struct RecordingNotifier {
+    shown: Mutex<Vec<(String, String)>>,
+}
+
+#[cfg(test)]
+impl RecordingNotifier {
+    /// The (title, message) pairs shown so far, oldest first.
+    pub fn shown(&self) -> Vec<(String, String)> {
+        self.shown.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl Notifier for RecordingNotifier {
+    fn notify(&self, title: &str, message: &str) -> NotifyResult {
+        self.shown.lock().unwrap().push((title.to_string(), message.to_string()));
+        Ok(())
+    }
+}
+
+/// Drops control characters (environment variables can't hold NUL) and keeps
+/// the text to a sensible length. Line breaks and tabs are kept.
+fn clean(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control() || *c == '\n' || *c == '\t')
+        .take(MAX_TEXT_CHARS)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cleans_text() {
+        assert_eq!(clean("nul\0byte"), "nulbyte");
+        assert_eq!(clean("bell\u{7}\r\n\tok"), "bell\n\tok");
+        assert_eq!(clean(&"é".repeat(1000)).chars().count(), MAX_TEXT_CHARS);
+        assert_eq!(clean("Profile Changed"), "Profile Changed");
+    }
+
+    #[test]
+    fn picks_backend_from_settings() {
+        let settings: NotifierSettings = serde_json::from_str(r#"{ "backend": "log" }"#).unwrap();
+        assert_eq!(settings.backend, NotifierKind::Log);
+        let settings: NotifierSettings = serde_json::from_str("{}").unwrap();
+        assert_eq!(settings.backend, NotifierKind::default());
+        assert!(serde_json::from_str::<NotifierSettings>(r#"{ "backend": "growl" }"#).is_err());
+    }
+
+    #[test]
+    fn reads_thresholds() {
+        let settings: NotifierSettings = serde_json::from_str(r#"{ "min_severity": "warning", "dedup_seconds": 2.5 }"#).unwrap();
+        assert_eq!(settings.min_severity, Severity::Warning);
+        assert_eq!(settings.dedup_window(), Duration::from_millis(2500));
+        assert!(Severity::Info < Severity::Warning && Severity::Warning < Severity::Error);
+        let settings = NotifierSettings::default();
+        assert_eq!(settings.min_severity, Severity::Info);
+        assert_eq!(settings.dedup_window(), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn records_notifications() {
+        let recorder = RecordingNotifier::default();
+        let notifier: &dyn Notifier = &recorder;
+        notifier.notify("Profile Changed", "Genshin profile is now active.").unwrap();
+        notifier.notify("MyMIDI", "game muted").unwrap();
+        assert_eq!(recorder.shown(), vec![
+            ("Profile Changed".to_string(), "Genshin profile is now active.".to_string()),
+            ("MyMIDI".to_string(), "game muted".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn log_and_none_never_fail() {
+        assert!(create(NotifierKind::Log).notify("title", "nul\0byte").is_ok());
+        assert!(create(NotifierKind::None).notify("title", "message").is_ok());
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant};
+use super::{Notification, Notifier, Severity};
+/*###############################################################################
+Notification Queue
+    Showing a notification can take a while (PowerShell has to start up), so
+    `send` only puts it in a queue and returns. One thread shows them, in order.
+    On the way it drops repeats: a warning or error whose key was shown less than
+    `dedup_seconds` ago is held back. When the window is over, the newest held
+    back one is shown once, saying how many were merged into it. A knob turned
+    while Sonar is down gives two "Volume Control Failed" toasts, not fifty.
+###############################################################################*/
+static QUEUE: OnceLock<Sender<Notification>> = OnceLock::new();
+
+/// Queues `notification`, starting the thread that shows them the first time.
+pub fn send(notification: Notification, notifier: &'static dyn Notifier, window: Duration) {
+    let queue = QUEUE.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || run(receiver, notifier, window));
+        sender
+    });
+    let _ = queue.send(notification);
+}
+
+fn run(receiver: Receiver<Notification>, notifier: &'static dyn Notifier, window: Duration) {
+    let mut coalescer = Coalescer::new(window);
+    loop {
+        let received = match coalescer.next_due() {
+            Some(due) => receiver.recv_timeout(due.saturating_duration_since(Instant::now())),
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let mut ready = Vec::new();
+        match received {
+            Ok(notification) => ready.extend(coalescer.offer(notification, Instant::now())),
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        ready.extend(coalescer.take_due(Instant::now()));
+        for notification in ready {
+            if let Err(e) = notifier.notify(&notification.title, &notification.message) {
+                println!("Could not show the \"{}: {}\" notification: {}", notification.title, notification.message, e);
+            }
+        }
+    }
+}
+
+struct Recent {
+    shown_at: Instant,
+    /// The newest notification held back since, and how many were.
+    held: Option<(Notification, usize)>,
+}
+
+/// Decides which notifications to show and when. Kept apart from the thread so
+/// it can be tested without waiting.
+pub struct Coalescer {
+    window: Duration,
+    recent: HashMap<String, Recent>,
+}
+
+impl Coalescer {
+    pub fn new(window: Duration) -> Self {
+        Coalescer { window, recent: HashMap::new() }
+    }
+
+    /// Returns `notification` if it should be shown now, or holds it back.
+    pub fn offer(&mut self, notification: Notification, now: Instant) -> Option<Notification> {
+        if notification.severity < Severity::Warning {
+            return Some(notification);
+        }
+        match self.recent.get_mut(&notification.key) {
+            Some(recent) if now < recent.shown_at + self.window => {
+                let count = recent.held.as_ref().map_or(0, |(_, count)| *count);
+                recent.held = Some((notification, count + 1));
+                None
+            },
+            _ => {
+                // One held back until now, but not taken yet, is merged into this one
+                let held = self.recent.remove(&notification.key).and_then(|recent| recent.held).map_or(0, |(_, count)| count);
+                self.recent.insert(notification.key.clone(), Recent { shown_at: now, held: None });
+                Some(with_count(notification, held + 1))
+            },
+        }
+    }
+
+    /// Held back notifications whose window is over, each with a note on how many it stands for.
+    pub fn take_due(&mut self, now: Instant) -> Vec<Notification> {
+        let window = self.window;
+        let mut due = Vec::new();
+        for recent in self.recent.values_mut() {
+            if now < recent.shown_at + window {
+                continue;
+            }
+            if let Some((notification, count)) = recent.held.take() {
+                recent.shown_at = now;
+                due.push(with_count(notification, count));
+            }
+        }
+        // Forget keys that have been quiet for a whole window
+        self.recent.retain(|_, recent| recent.held.is_some() || now < recent.shown_at + window);
+        due
+    }
+
+    /// When the next held back notification is due, if there is one.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.recent.values()
+            .filter(|recent| recent.held.is_some())
+            .map(|recent| recent.shown_at + self.window)
+            .min()
+    }
+}
+
+/// Notes in the message how many notifications `notification` stands for.
+fn with_count(mut notification: Notification, count: usize) -> Notification {
+    if count > 1 {
+        notification.message = format!("{} ({} times)", notification.message, count);
+    }
+    notification
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failure(message: &str) -> Notification {
+        Notification::new(Severity::Error, "Volume Control Failed", message)
+    }
+
+    #[test]
+    fn drops_repeats_within_the_window() {
+        let start = Instant::now();
+        let mut coalescer = Coalescer::new(Duration::from_secs(10));
+        assert!(coalescer.offer(failure("first"), start).is_some());
+        for i in 1..50 {
+            let now = start + Duration::from_millis(100 * i);
+            assert!(coalescer.offer(failure(&format!("try {}", i)), now).is_none());
+            assert!(coalescer.take_due(now).is_empty());
+        }
+        assert_eq!(coalescer.next_due(), Some(start + Duration::from_secs(10)));
+
+        let due = coalescer.take_due(start + Duration::from_secs(10));
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].message, "try 49 (49 times)");
+        assert_eq!(coalescer.next_due(), None);
+    }
+
+    #[test]
+    fn keys_are_separate() {
+        let now = Instant::now();
+        let mut coalescer = Coalescer::new(Duration::from_secs(10));
+        assert!(coalescer.offer(failure("a"), now).is_some());
+        assert!(coalescer.offer(Notification::new(Severity::Warning, "Mappings Not Reloaded", "bad json"), now).is_some());
+        assert!(coalescer.offer(failure("b").with_key("volume:game"), now).is_some());
+        assert!(coalescer.offer(failure("c"), now).is_none());
+    }
+
+    #[test]
+    fn shows_again_after_a_quiet_window() {
+        let start = Instant::now();
+        let mut coalescer = Coalescer::new(Duration::from_secs(10));
+        assert!(coalescer.offer(failure("a"), start).is_some());
+        assert!(coalescer.offer(failure("b"), start + Duration::from_secs(1)).is_none());
+        let due = coalescer.take_due(start + Duration::from_secs(11));
+        assert_eq!(due[0].message, "b");
+        // Shown at 11s, so the next one waits until 21s
+        assert!(coalescer.offer(failure("c"), start + Duration::from_secs(15)).is_none());
+        assert!(coalescer.take_due(start + Duration::from_secs(21)).len() == 1);
+        assert!(coalescer.take_due(start + Duration::from_secs(40)).is_empty());
+        assert!(coalescer.offer(failure("d"), start + Duration::from_secs(40)).is_some());
+    }
+
+    #[test]
+    fn a_repeat_after_the_window_keeps_the_held_count() {
+        let start = Instant::now();
+        let mut coalescer = Coalescer::new(Duration::from_secs(10));
+        assert!(coalescer.offer(failure("a"), start).is_some());
+        assert!(coalescer.offer(failure("b"), start + Duration::from_secs(1)).is_none());
+        assert!(coalescer.offer(failure("c"), start + Duration::from_secs(2)).is_none());
+        // The window is over, but take_due hasn't run yet
+        let shown = coalescer.offer(failure("d"), start + Duration::from_secs(12)).unwrap();
+        assert_eq!(shown.message, "d (3 times)");
+        assert!(coalescer.take_due(start + Duration::from_secs(12)).is_empty());
+        assert_eq!(coalescer.next_due(), None);
+    }
+
+    #[test]
+    fn info_is_never_held_back() {
+        let now = Instant::now();
+        let mut coalescer = Coalescer::new(Duration::from_secs(10));
+        for message in ["game muted", "game unmuted", "game muted"] {
+            assert!(coalescer.offer(Notification::new(Severity::Info, "MyMIDI", message), now).is_some());
+        }
+    }
+
+    #[test]
+    fn no_window_shows_everything() {
+        let now = Instant::now();
+        let mut coalescer = Coalescer::new(Duration::ZERO);
+        assert!(coalescer.offer(failure("a"), now).is_some());
+        assert!(coalescer.offer(failure("b"), now).is_some());
+    }
+}
@@ -0,0 +1,130 @@
+use std::process::Command;
+use super::{clean, Notifier, NotifyResult};
+/*###############################################################################
+Windows Toasts
+    The toast is shown by a PowerShell script. The script is a constant and the
+    title and message are handed over in environment variables, which PowerShell
+    only ever reads as plain strings.
+###############################################################################*/
+/// Runs a fixed program with fixed arguments, passing the title and message in the
+/// MYMIDI_TOAST_TITLE and MYMIDI_TOAST_MESSAGE environment variables.
+pub struct CommandNotifier {
+    program: String,
+    args: Vec<String>,
+}
+
+const TOAST_SCRIPT: &str = r#"
+[Windows.UI.Notifications.ToastNotificationManager, Windows.UI.Notifications, ContentType = WindowsRuntime] > $null
+$template = [Windows.UI.Notifications.ToastNotificationManager]::GetTemplateContent([Windows.UI.Notifications.ToastTemplateType]::ToastText02)
+
+$textNodes = $template.GetElementsByTagName("text")
+$textNodes.Item(0).AppendChild($template.CreateTextNode($env:MYMIDI_TOAST_TITLE)) > $null
+$textNodes.Item(1).AppendChild($template.CreateTextNode($env:MYMIDI_TOAST_MESSAGE)) > $null
+
+$toast = [Windows.UI.Notifications.ToastNotification]::new($template)
+$notifier = [Windows.UI.Notifications.ToastNotificationManager]::CreateToastNotifier("MyMIDI")
+$notifier.Show($toast)
+"#;
+
+impl CommandNotifier {
+    pub fn new(program: &str, args: &[&str]) -> Self {
+        CommandNotifier {
+            program: program.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        }
+    }
+
+    /// A Windows toast, shown through PowerShell.
+    pub fn powershell_toast() -> Self {
+        CommandNotifier::new("powershell", &["-NoProfile", "-NonInteractive", "-ExecutionPolicy", "Bypass", "-Command", TOAST_SCRIPT])
+    }
+
+    fn command(&self, title: &str, message: &str) -> Command {
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .env("MYMIDI_TOAST_TITLE", clean(title))
+            .env("MYMIDI_TOAST_MESSAGE", clean(message));
+        command
+    }
+}
+
+impl Notifier for CommandNotifier {
+    fn notify(&self, title: &str, message: &str) -> NotifyResult {
+        let output = self.command(title, message).output()
+            .map_err(|e| format!("Could not run {}: {}", self.program, e))?;
+        if !output.status.success() {
+            return Err(format!("{} failed: {}", self.program, String::from_utf8_lossy(&output.stderr).trim()).into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    const SENTINEL: &str = "/tmp/mymidi-notifier-pwned";
+
+    /// Text that would have run code when it was pasted into the script.
+    fn hostile_strings() -> Vec<String> {
+        vec![
+            r#"")) > $null; Start-Process calc; (("#.to_string(),
+            r#"$(Start-Process calc)"#.to_string(),
+            r#"`"; Start-Process calc; `""#.to_string(),
+            r#"'; Start-Process calc; '"#.to_string(),
+            "@\"\nStart-Process calc\n\"@".to_string(),
+            "line one\nStart-Process calc".to_string(),
+            "$env:USERNAME $env:MYMIDI_TOAST_TITLE".to_string(),
+            "%PATH% !PATH! ^& calc".to_string(),
+            "<toast><visual><binding template=\"x\"/></visual></toast> & &amp; ]]>".to_string(),
+            format!("$(touch {}) `touch {}` ; touch {}", SENTINEL, SENTINEL, SENTINEL),
+            r#"\" \\" \\\" C:\Program Files\App\"#.to_string(),
+            "ünïcödé 🎹 \u{202e}txt.exe".to_string(),
+            "nul\0byte".to_string(),
+            "x".repeat(10_000),
+            String::new(),
+        ]
+    }
+
+    /// Prints the two environment variables back, separated by a NUL.
+    #[cfg(unix)]
+    fn echo_notifier() -> CommandNotifier {
+        CommandNotifier::new("sh", &["-c", r#"printf '%s\0%s' "$MYMIDI_TOAST_TITLE" "$MYMIDI_TOAST_MESSAGE""#])
+    }
+
+    #[test]
+    fn the_script_never_changes() {
+        let toast = CommandNotifier::powershell_toast();
+        let plain = toast.command("Title", "Message");
+        let expected: Vec<&OsStr> = plain.get_args().collect();
+        for text in hostile_strings() {
+            let command = toast.command(&text, &text);
+            assert_eq!(command.get_program(), plain.get_program());
+            assert_eq!(command.get_args().collect::<Vec<_>>(), expected, "arguments changed for {:?}", text);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn text_arrives_unchanged() {
+        let _ = std::fs::remove_file(SENTINEL);
+        let notifier = echo_notifier();
+        for text in hostile_strings() {
+            let output = notifier.command(&text, &text).output().unwrap();
+            assert!(output.status.success());
+            let expected = clean(&text);
+            assert_eq!(String::from_utf8(output.stdout).unwrap(), format!("{}\0{}", expected, expected));
+        }
+        assert!(!std::path::Path::new(SENTINEL).exists(), "a hostile string ran a command");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reports_failures() {
+        assert!(CommandNotifier::new("sh", &["-c", "exit 3"]).notify("a", "b").is_err());
+        assert!(CommandNotifier::new("mymidi-no-such-program", &[]).notify("a", "b").is_err());
+        assert!(echo_notifier().notify("a", "b").is_ok());
+    }
+}
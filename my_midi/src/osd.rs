@@ -0,0 +1,186 @@
+// osd.rs
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::OnceLock;
+use std::thread;
+use serde::Deserialize;
+use crate::midi_commands;
+use crate::notifier::desktop::{gvariant_string, DesktopNotifier, Hint};
+use crate::notifier::NotifyResult;
+/*###############################################################################
+On-Screen Display
+    Quick feedback while playing: the channel and its level while a volume knob
+    turns, and the profile or Genshin music layout after switching. It's off
+    unless settings.json turns it on:
+        "osd": { "backend": "desktop" }
+    Backends:
+        desktop  A notification with a progress bar, over D-Bus (Linux). Each
+                 channel keeps a single popup that is updated in place, so a knob
+                 turn doesn't stack up dozens of them. Most notification servers
+                 (KDE, dunst, mako, xfce4-notifyd) draw the bar; GNOME shows the
+                 percentage only.
+        log      Print to the console.
+        none     No OSD (the default). Profile and layout changes are shown as
+                 regular toasts instead.
+    Updates are shown on a thread of their own. If several come in for the same
+    thing while one is being shown, only the newest is shown next.
+###############################################################################*/
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OsdKind {
+    Desktop,
+    Log,
+    #[default]
+    None,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct OsdSettings {
+    pub backend: OsdKind,
+}
+
+/// How long the OSD stays up after the last update, in milliseconds.
+const TIMEOUT_MS: i32 = 1500;
+
+#[derive(Debug, Clone, PartialEq)]
+struct Update {
+    /// Updates with the same key replace each other on screen.
+    key: String,
+    title: String,
+    text: String,
+    percent: Option<u8>,
+}
+
+trait Display: Send {
+    fn show(&mut self, update: &Update) -> NotifyResult;
+}
+
+static OSD: OnceLock<Sender<Update>> = OnceLock::new();
+
+/// Starts the OSD picked in the settings. Call it once, at startup.
+pub fn install(settings: &OsdSettings) {
+    let mut display: Box<dyn Display> = match settings.backend {
+        OsdKind::Desktop => Box::new(DesktopOsd { notifier: DesktopNotifier::new(), ids: HashMap::new() }),
+        OsdKind::Log => Box::new(LogOsd),
+        OsdKind::None => return,
+    };
+    let (sender, receiver) = mpsc::channel();
+    if OSD.set(sender).is_err() {
+        println!("The OSD was already set up.");
+        return;
+    }
+    thread::spawn(move || run(receiver, display.as_mut()));
+}
+
+/// Shows `label` at `level` (0.0 to 1.0), if the OSD is on.
+pub fn level(label: &str, level: f32) {
+    if let Some(osd) = OSD.get() {
+        let percent = (level.clamp(0.0, 1.0) * 100.0).round() as u8;
+        let _ = osd.send(Update { key: format!("level:{}", label), title: label.to_string(), text: format!("{}%", percent), percent: Some(percent) });
+    }
+}
+
+/// Shows a short status line, like the active profile. Without an OSD it is
+/// shown as a toast.
+pub fn status(title: &str, text: &str) {
+    match OSD.get() {
+        Some(osd) => {
+            let _ = osd.send(Update { key: format!("status:{}", title), title: title.to_string(), text: text.to_string(), percent: None });
+        },
+        None => midi_commands::show_toast(title, text),
+    }
+}
+
+fn run(receiver: Receiver<Update>, display: &mut dyn Display) {
+    let mut failing = false;
+    while let Ok(first) = receiver.recv() {
+        for update in latest_per_key(first, receiver.try_iter()) {
+            match display.show(&update) {
+                Ok(()) => failing = false,
+                // Only say so once, not for every step of a knob turn
+                Err(e) if !failing => {
+                    println!("Could not show the OSD: {}", e);
+                    failing = true;
+                },
+                Err(_) => {},
+            }
+        }
+    }
+}
+
+/// Drops every update that a later one with the same key replaces.
+fn latest_per_key(first: Update, rest: impl Iterator<Item = Update>) -> Vec<Update> {
+    let mut updates: Vec<Update> = vec![first];
+    for update in rest {
+        match updates.iter_mut().find(|queued| queued.key == update.key) {
+            Some(queued) => *queued = update,
+            None => updates.push(update),
+        }
+    }
+    updates
+}
+
+struct DesktopOsd {
+    notifier: DesktopNotifier,
+    /// The id of the popup shown for each key, so the next update replaces it.
+    ids: HashMap<String, u32>,
+}
+
+impl Display for DesktopOsd {
+    fn show(&mut self, update: &Update) -> NotifyResult {
+        let tag = format!("<{}>", gvariant_string(&format!("mymidi:{}", update.key)));
+        let mut hints: Vec<Hint> = vec![
+            ("x-canonical-private-synchronous", tag.clone()),
+            ("x-dunst-stack-tag", tag),
+            ("urgency", String::from("<byte 0>")),
+            ("transient", String::from("<true>")),
+        ];
+        if let Some(percent) = update.percent {
+            hints.push(("value", format!("<int32 {}>", percent)));
+        }
+        let replaces = self.ids.get(&update.key).copied().unwrap_or(0);
+        let id = self.notifier.show(&update.title, &update.text, replaces, &hints, TIMEOUT_MS)?;
+        self.ids.insert(update.key.clone(), id);
+        Ok(())
+    }
+}
+
+struct LogOsd;
+
+impl Display for LogOsd {
+    fn show(&mut self, update: &Update) -> NotifyResult {
+        match update.percent {
+            Some(percent) => {
+                let filled = percent as usize / 5;
+                println!("{} [{}{}] {}", update.title, "#".repeat(filled), "-".repeat(20 - filled), update.text);
+            },
+            None => println!("{}: {}", update.title, update.text),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level_update(label: &str, percent: u8) -> Update {
+        Update { key: format!("level:{}", label), title: label.to_string(), text: format!("{}%", percent), percent: Some(percent) }
+    }
+
+    #[test]
+    fn keeps_only_the_newest_update_per_key() {
+        let rest = vec![level_update("game", 11), level_update("master", 12), level_update("master", 13), level_update("game", 14)];
+        let updates = latest_per_key(level_update("master", 10), rest.into_iter());
+        assert_eq!(updates, vec![level_update("master", 13), level_update("game", 14)]);
+    }
+
+    #[test]
+    fn off_by_default() {
+        let settings: OsdSettings = serde_json::from_str("{}").unwrap();
+        assert_eq!(settings.backend, OsdKind::None);
+        let settings: OsdSettings = serde_json::from_str(r#"{ "backend": "desktop" }"#).unwrap();
+        assert_eq!(settings.backend, OsdKind::Desktop);
+    }
+}
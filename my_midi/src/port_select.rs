@@ -0,0 +1,154 @@
+// port_select.rs
+use std::collections::HashSet;
+use regex::Regex;
+/*###############################################################################
+MIDI Port Selection
+    Picks which MIDI input port to listen on instead of always taking the first
+    one, which is often the wrong device (or a virtual "Midi Through" port on Linux).
+    Ports are chosen with selectors, written as:
+        MPK Mini        any port whose name contains "MPK Mini" (ignoring case)
+        exact:MPK Mini Play 0
+                        the port with exactly this name
+        regex:^MPK.*    any port whose name matches the regular expression
+        index:1         the second port, in the order `--list-ports` prints them
+    Selectors are tried in order, so a list works as a fallback priority list.
+    Every free port the first matching selector matches is opened, each with its
+    own connection, so "MPK" listens on every MPK plugged in while "index:0"
+    only ever means one port. A later selector is only used when none of the
+    ones before it match anything.
+    midi_key_mapper is its own crate, so it has a smaller copy of this in
+    `port_matches`: the same syntax without regex:, opening only the first match.
+###############################################################################*/
+/// The name part of a selector: "exact:", "regex:" or part of the name. Window
+/// rules (window_watcher.rs) match names with it too.
+#[derive(Debug, Clone)]
+pub enum NamePattern {
+    Exact(String),
+    Contains(String),
+    Regex(Regex),
+}
+
+impl NamePattern {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        if let Some(name) = text.strip_prefix("exact:") {
+            Ok(NamePattern::Exact(name.to_string()))
+        } else if let Some(pattern) = text.strip_prefix("regex:") {
+            Regex::new(pattern)
+                .map(NamePattern::Regex)
+                .map_err(|e| format!("Invalid pattern '{}': {}", pattern, e))
+        } else {
+            Ok(NamePattern::Contains(text.to_lowercase()))
+        }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            NamePattern::Exact(exact) => name == exact,
+            NamePattern::Contains(part) => name.to_lowercase().contains(part),
+            NamePattern::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PortSelector {
+    Name(NamePattern),
+    Index(usize),
+}
+
+impl PortSelector {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        match text.strip_prefix("index:") {
+            Some(index) => index.trim().parse()
+                .map(PortSelector::Index)
+                .map_err(|_| format!("Invalid port index '{}'", index)),
+            None => NamePattern::parse(text).map(PortSelector::Name),
+        }
+    }
+
+    pub fn matches(&self, index: usize, name: &str) -> bool {
+        match self {
+            PortSelector::Name(pattern) => pattern.matches(name),
+            PortSelector::Index(wanted) => index == *wanted,
+        }
+    }
+}
+
+/// Returns the index of the next port to open for a device: the first of its
+/// `matching_ports` that isn't open already. Ports named in `taken` are open for
+/// this or another device and are skipped.
+pub fn select_port(selectors: &[PortSelector], names: &[String], taken: &HashSet<String>) -> Option<usize> {
+    matching_ports(selectors, names).into_iter().find(|index| !taken.contains(&names[*index]))
+}
+
+/// The ports a device listens on: every port the first selector that matches
+/// anything matches. With no selectors, only the first port is used.
+pub fn matching_ports(selectors: &[PortSelector], names: &[String]) -> Vec<usize> {
+    if selectors.is_empty() {
+        return (0..names.len().min(1)).collect();
+    }
+    selectors.iter()
+        .map(|selector| (0..names.len()).filter(|index| selector.matches(*index, &names[*index])).collect::<Vec<_>>())
+        .find(|matches| !matches.is_empty())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selectors(texts: &[&str]) -> Vec<PortSelector> {
+        texts.iter().map(|text| PortSelector::parse(text).unwrap()).collect()
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn a_selector_matches_every_port_it_fits() {
+        let ports = names(&["Midi Through", "MPK Mini Play 0", "MPK Mini Play 1"]);
+        assert_eq!(matching_ports(&selectors(&["mpk"]), &ports), vec![1, 2]);
+        assert_eq!(matching_ports(&selectors(&["exact:MPK Mini Play 1", "mpk"]), &ports), vec![2]);
+        assert_eq!(matching_ports(&selectors(&["Knob", "index:0"]), &ports), vec![0]);
+        assert_eq!(matching_ports(&[], &ports), vec![0]);
+        assert!(matching_ports(&selectors(&["Knob"]), &ports).is_empty());
+    }
+
+    #[test]
+    fn parses_selectors() {
+        assert!(matches!(PortSelector::parse("exact:MPK Mini Play 0"), Ok(PortSelector::Name(NamePattern::Exact(name))) if name == "MPK Mini Play 0"));
+        assert!(matches!(PortSelector::parse("MPK Mini"), Ok(PortSelector::Name(NamePattern::Contains(part))) if part == "mpk mini"));
+        assert!(matches!(PortSelector::parse("regex:^MPK"), Ok(PortSelector::Name(NamePattern::Regex(_)))));
+        assert!(matches!(PortSelector::parse("index: 2"), Ok(PortSelector::Index(2))));
+        assert!(PortSelector::parse("regex:(").is_err());
+        assert!(PortSelector::parse("index:two").is_err());
+    }
+
+    #[test]
+    fn each_kind_of_selector_matches() {
+        let ports = names(&["Midi Through", "MPK Mini Play 0", "Knob Box"]);
+        let none = HashSet::new();
+        assert_eq!(select_port(&selectors(&["exact:Knob Box"]), &ports, &none), Some(2));
+        assert_eq!(select_port(&selectors(&["exact:knob box"]), &ports, &none), None);
+        assert_eq!(select_port(&selectors(&["regex:^MPK.*0$"]), &ports, &none), Some(1));
+        assert_eq!(select_port(&selectors(&["index:2"]), &ports, &none), Some(2));
+        assert_eq!(select_port(&selectors(&["index:3"]), &ports, &none), None);
+        assert_eq!(select_port(&selectors(&["knob"]), &ports, &none), Some(2));
+    }
+
+    #[test]
+    fn earlier_selectors_win_and_taken_ports_are_skipped() {
+        let ports = names(&["Midi Through", "MPK Mini Play 0", "MPK Mini Play 1"]);
+        let none = HashSet::new();
+        assert_eq!(select_port(&selectors(&["MPK", "index:0"]), &ports, &none), Some(1));
+        assert_eq!(select_port(&selectors(&["Knob", "index:0"]), &ports, &none), Some(0));
+        let taken: HashSet<String> = names(&["MPK Mini Play 0"]).into_iter().collect();
+        assert_eq!(select_port(&selectors(&["MPK", "index:0"]), &ports, &taken), Some(2));
+        // Every MPK is open, so the fallback isn't used for another connection
+        let taken: HashSet<String> = names(&["MPK Mini Play 0", "MPK Mini Play 1"]).into_iter().collect();
+        assert_eq!(select_port(&selectors(&["MPK", "index:0"]), &ports, &taken), None);
+        assert_eq!(select_port(&[], &ports, &none), Some(0));
+        assert_eq!(select_port(&[], &names(&[]), &none), None);
+    }
+}
@@ -1,12 +1,12 @@
 // genshin.rs
 use std::collections::HashMap;
 use std::sync::Mutex;
-use std::env;
 use enigo::{
-    Direction::{Click, Press, Release},
-    Enigo, Key, Keyboard,Settings,
+    Direction::{Press, Release},
+    Key, Keyboard,
 };
-use crate::midi_commands;
+use crate::midi_message::MidiMessage;
+use crate::profiles::{Context, Profile};
 /*###############################################################################
 Music Layouts
     Genshin allows you to play music using the keyboard by clicking specific keys. 
@@ -27,9 +27,11 @@ Music Layouts
     all playable notes in Genshin by utilizing the black piano keys, even though Genshin plays in
     the scale of C which does not use black keys. The 'Highs' and 'Lows' use only white keys,
     i.e. standards C scale, but do not cover all playable notes.
-    You can define the default music layout in this section.
-    We use lazy_static method for caching. It contains CURRENT_SCALE, which holds
-    the currently selected music layout.
+    The 'Complete' layout is played by default. The layout pad in `mappings/genshin.json`
+    cycles through the "lows" and "highs" layers (see layers.rs), and while one of them
+    is on, the piano keys use that layout instead. Bind "layer" to a pad instead to
+    only play Lows or Highs while it is held.
+    We use lazy_static method for caching the layouts.
 ###############################################################################*/
 // Define an enum to represent the current scale state
 enum ScaleType {
@@ -54,86 +56,75 @@ lazy_static! {
         (48, 'a'), (50, 's'), (52, 'd'), (53, 'f'), (55, 'g'), (57, 'h'), (59, 'j'),
         (60, 'q'), (62, 'w'), (64, 'e'), (65, 'r'), (67, 't'), (69, 'y'), (71, 'u'),
     ]));
+}
 
-    // Set Default profile.
-    static ref CURRENT_SCALE: Mutex<ScaleType> = Mutex::new(ScaleType::Complete);
+pub struct Genshin {
+    // Keys currently held down by piano keys, by note, so a note is released with
+    // the key it pressed even if the layout changed in between, and everything
+    // can be released when switching profiles mid-note.
+    held_keys: HashMap<u8, char>,
 }
 
-pub fn handle_message(enigo: &mut Enigo, button_states: &mut HashMap<u8, bool>, message: &[u8]) {
-    /****************************************************************************** 
-    This part is for switching between music layouts. If you only have one layout and don't need to switch,
-    you can probably remove this.
-    Determine CURRENT_SCALE and directly work with its lock guard.
-    ******************************************************************************/
-    let scale_guard = match *CURRENT_SCALE.lock().unwrap() {
-        ScaleType::Lows => LOWS.lock().unwrap(),
-        ScaleType::Highs => HIGHS.lock().unwrap(),
-        ScaleType::Complete => COMPLETE.lock().unwrap(),
-    };
-    /*###############################################################################
-    Button Assignment 
-        Drum pad buttons on my MIDI send a signal of the form [153,n,v].
-        So for an incomming message, I first check if the first number is 153.
-        Then I check for the n for each specific button I wish to assign.
-    ###############################################################################*/
-    if message[0] == 153 { // Adjust channel checking as needed
-        let note = message[1]; // MIDI note number
-        
-        /*###############################################################################
-        Music Layout Switching
-            If your MIDI is large enough to cover all notes, you may not need this button.
-        ###############################################################################*/
-        if note == 40 {
-            let mut scale = CURRENT_SCALE.lock().unwrap();
-            *scale = match *scale {
-                ScaleType::Complete => {
-                    //println!("Toggled to Complete Layout");
-                    midi_commands::show_toast("Music Layout Change", "Toggled to Layout 2: Lows");
-                    ScaleType::Lows
-                },
-                ScaleType::Lows => {
-                    //println!("Toggled to Highs");
-                    midi_commands::show_toast("Music Layout Change","Toggled to Layout 2: Highs");
-                    ScaleType::Highs
-                },
-                ScaleType::Highs => {
-                    //println!("Toggled to Lows");
-                    midi_commands::show_toast("Music Layout Change","Toggled to Layout 1: Complete");
-                    ScaleType::Complete
-                },
-            };
+impl Genshin {
+    pub fn new() -> Self {
+        Genshin {
+            held_keys: HashMap::new(),
         }
+    }
+}
+
+impl Profile for Genshin {
+    fn name(&self) -> &str {
+        "Genshin"
+    }
+
+    fn handle(&mut self, ctx: &mut Context, message: &MidiMessage) {
+        /****************************************************************************** 
+        This part is for switching between music layouts. If you only have one layout and don't need to switch,
+        you can probably remove this.
+        The topmost layout layer that is on picks the scale. Work directly with its lock guard.
+        ******************************************************************************/
+        let scale = ctx.layers.active().into_iter().find_map(|layer| match layer {
+            "lows" => Some(ScaleType::Lows),
+            "highs" => Some(ScaleType::Highs),
+            _ => None,
+        });
+        let scale_guard = match scale.unwrap_or(ScaleType::Complete) {
+            ScaleType::Lows => LOWS.lock().unwrap(),
+            ScaleType::Highs => HIGHS.lock().unwrap(),
+            ScaleType::Complete => COMPLETE.lock().unwrap(),
+        };
         /*###############################################################################
-        Drum Pad  Assignments
+        Button Assignment 
+            Drum pad buttons on my MIDI send a note on on channel 10, i.e. [153,n,v].
+            Piano keys send a note on on channel 1 when pressed ([144,n,v]) and a note off
+            when released ([128,n,v]). So for an incomming message, I match on the channel
+            and then on the n for each specific button I wish to assign.
         ###############################################################################*/
-        // Launch HoyoLab in a browser. For daily checkins and things.
-        if note == 41 {
-            // Open HoyoLab in a browser - For Daily Check ins
-            let url = "https://www.hoyolab.com/";
-            crate::midi_commands::open_url(url);
-        }
-        // Open Teyvat Map in a browser
-        if note == 42 {
-            // Open Teyvat Map in a browser
-            let url = "https://act.hoyolab.com/ys/app/interactive-map/index.html?bbs_presentation_style=no_header&utm_id=2&utm_medium=tool&utm_source=hoyolab&bbs_theme=dark&bbs_theme_device=1&lang=en-us#/map/2?shown_types=&center=2008.50,-1084.00&zoom=-3.00";
-            crate::midi_commands::open_url(url);
+        match *message {
+            // The music layout, HoyoLab and Teyvat Map pads are in `mappings/genshin.json`.
+
+            MidiMessage::NoteOn { channel: 1, note, .. } => {
+                if let Some(&key) = scale_guard.get(&note) {
+                    let _ = ctx.enigo.key(Key::Unicode(key), Press);
+                    self.held_keys.insert(note, key);
+                    println!("Key '{}' pressed.", key);
+                }
+            },
+            MidiMessage::NoteOff { channel: 1, note, .. } => {
+                if let Some(key) = self.held_keys.remove(&note) {
+                    let _ = ctx.enigo.key(Key::Unicode(key), Release);
+                    println!("Key '{}' released.", key);
+                }
+            },
+            _ => {},
         }
     }
 
-    let is_piano_pressed = message[0] == 144;
-    let is_piano_release = message[0] == 128;
-
-    if is_piano_pressed {
-        let note = message[1];
-        if let Some(&key) = scale_guard.get(&note) {
-            enigo.key(Key::Unicode(key), Press);
-            println!("Key '{}' pressed.", key);
-        }
-    } else if is_piano_release {
-        let note = message[1];
-        if let Some(&key) = scale_guard.get(&note) {
-            enigo.key(Key::Unicode(key), Release);
-            println!("Key '{}' released.", key);
+    fn on_deactivate(&mut self, ctx: &mut Context) {
+        // Don't leave a key stuck down if the profile changes while a note is held
+        for (_, key) in self.held_keys.drain() {
+            let _ = ctx.enigo.key(Key::Unicode(key), Release);
         }
     }
 }
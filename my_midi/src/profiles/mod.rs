@@ -1,3 +1,120 @@
+use std::sync::{Arc, Mutex};
+use enigo::Enigo;
+use crate::executor::Executor;
+use crate::layers::LayerStack;
+use crate::macro_recorder::MacroRecorder;
+use crate::macros::MacroRunner;
+use crate::steelseries_sonar_api::Sonar;
+use crate::volume::Volume;
+use crate::volume_throttle::VolumeThrottle;
+use crate::midi_message::MidiMessage;
+
 pub mod default;
 pub mod genshin;
-pub mod zenless_zone_zero;
\ No newline at end of file
+pub mod zenless_zone_zero;
+
+/*###############################################################################
+Profiles
+    Adding a new profile requires a few steps.
+        1. Create `src/profiles/profile_name.rs` with a struct that implements `Profile`.
+        2. Add `pub mod profile_name;` above and add the struct to `registry()` below.
+    The profile change button cycles through profiles in the order they are registered.
+    Simple button assignments for the profile can go in a mapping file with
+    "profile" set to the profile's name (see mappings.rs).
+###############################################################################*/
+pub fn registry() -> ProfileRegistry {
+    ProfileRegistry::new(vec![
+        Box::new(default::Default),
+        Box::new(zenless_zone_zero::ZenlessZoneZero),
+        Box::new(genshin::Genshin::new()),
+    ])
+}
+
+/// What a profile gets to work with while handling a message.
+pub struct Context<'a> {
+    pub enigo: &'a mut Enigo,
+    /// The layers held or latched on the device the message came from (see layers.rs).
+    pub layers: &'a mut LayerStack,
+    /// Run anything slow (HTTP requests, launching apps) here so the next
+    /// MIDI message isn't kept waiting.
+    pub executor: &'a Executor,
+    /// Knob-driven requests go through here so only the newest value is sent.
+    pub throttle: &'a VolumeThrottle,
+    /// Whichever volume backend settings.json picked. Use it from an executor job.
+    pub volume: &'a Arc<Volume>,
+    /// Only there when Sonar is the volume backend. Lock it inside an executor job,
+    /// never on the thread handling messages.
+    pub sonar: &'a Option<Arc<Mutex<Sonar>>>,
+    /// Plays macros on their own thread. Use it for anything that needs pauses
+    /// or holds keys down.
+    pub macros: &'a MacroRunner,
+    /// The recorder behind the "record_macro" action.
+    pub recorder: &'a MacroRecorder,
+}
+
+pub trait Profile: Send {
+    /// Shown in toasts and matched against "profile" in mapping files.
+    fn name(&self) -> &str;
+
+    /// Called for every message while the profile is active.
+    fn handle(&mut self, ctx: &mut Context, msg: &MidiMessage);
+
+    /// Called when the profile becomes the active one.
+    fn on_activate(&mut self, _ctx: &mut Context) {}
+
+    /// Called when switching away from the profile. Release anything still held here.
+    fn on_deactivate(&mut self, _ctx: &mut Context) {}
+}
+
+/// Every registered profile plus which one is active.
+pub struct ProfileRegistry {
+    profiles: Vec<Box<dyn Profile>>,
+    active: usize,
+}
+
+impl ProfileRegistry {
+    /// The first profile starts out active.
+    pub fn new(profiles: Vec<Box<dyn Profile>>) -> Self {
+        assert!(!profiles.is_empty(), "at least one profile has to be registered");
+        ProfileRegistry { profiles, active: 0 }
+    }
+
+    /// Every profile's name, in the order they were registered.
+    pub fn names(&self) -> Vec<&str> {
+        self.profiles.iter().map(|profile| profile.name()).collect()
+    }
+
+    pub fn active_name(&self) -> &str {
+        self.profiles[self.active].name()
+    }
+
+    /// Switches to the next registered profile and returns its name.
+    pub fn cycle(&mut self, ctx: &mut Context) -> &str {
+        let next = (self.active + 1) % self.profiles.len();
+        self.switch_to(next, ctx);
+        self.active_name()
+    }
+
+    /// Switches to the profile called `name`. Returns false if there is no such profile.
+    pub fn activate(&mut self, name: &str, ctx: &mut Context) -> bool {
+        match self.profiles.iter().position(|profile| profile.name() == name) {
+            Some(index) => {
+                if index != self.active {
+                    self.switch_to(index, ctx);
+                }
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn switch_to(&mut self, index: usize, ctx: &mut Context) {
+        self.profiles[self.active].on_deactivate(ctx);
+        self.active = index;
+        self.profiles[self.active].on_activate(ctx);
+    }
+
+    pub fn handle(&mut self, ctx: &mut Context, msg: &MidiMessage) {
+        self.profiles[self.active].handle(ctx, msg);
+    }
+}
@@ -0,0 +1,200 @@
+// settings.rs
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use crate::gestures::GestureSettings;
+use crate::notifier::NotifierSettings;
+use crate::osd::OsdSettings;
+use crate::port_select::PortSelector;
+use crate::steelseries_sonar_api;
+use crate::volume::VolumeSettings;
+use crate::window_watcher::AutoProfileSettings;
+/*###############################################################################
+Settings
+    App-wide settings are read from the `settings.json` next to the executable,
+    or in a folder above it (`my_midi/settings.json` for a cargo build), or from
+    the file given with `--config <path>`. Every setting is optional; a missing file means
+    "use the defaults".
+    {
+        "ports": ["MPK Mini Play", "index:0"]
+    }
+        ports   MIDI input ports to try, in order of preference. See port_select.rs
+                for the selector syntax. Every port the first matching selector
+                matches is opened, so two MPKs plugged in both work with "MPK".
+                Leave empty to use the first port.
+        devices Listen on several devices at once. Each device picks its own ports
+                (from its own `ports` list), keeps its own active profile and
+                reconnects on its own. When set, the top level `ports` is ignored.
+                Each port a device opens keeps its own profile and layers too.
+                    "devices": [
+                        { "name": "pads", "ports": ["MPK Mini Play"], "profile": "Default" },
+                        { "name": "knobs", "ports": ["regex:^Knob"] }
+                    ]
+                `name` is what "device" in a mapping trigger matches. It defaults to
+                the port name. `profile` is the profile the device starts in.
+        volume_updates_per_second
+                The most volume requests sent per second for each channel while a
+                knob is turning, from 1 to 1000 (default 20). See volume_throttle.rs.
+        volume  Which volume backend knobs and mute pads control, and what the
+                channel names in mappings mean. See volume/mod.rs.
+                    "volume": { "backend": "pulse", "channels": { "game": "master" } }
+        sonar   SteelSeries Sonar options.
+                    "sonar": { "mode": "auto", "database": "D:\\Sonar\\database.db" }
+                `mode` is "auto" (read it from Sonar's database), "classic" or "streamer".
+                `database` is where Sonar keeps its database, if not in the usual
+                place under ProgramData.
+        notifications
+                How toasts are shown: "windows", "desktop" (Linux), "log" or "none".
+                    "notifications": { "backend": "log" }
+                The default suits the platform. See notifier/mod.rs.
+        osd     Show the level while a volume knob turns, and profile and layout
+                changes, on screen. "desktop" (Linux), "log" or "none" (the default).
+                    "osd": { "backend": "desktop" }
+                See osd.rs.
+        gestures
+                How long a long press, a double tap and the hold_repeat interval are.
+                    "gestures": { "long_press_ms": 500, "double_tap_ms": 250, "repeat_ms": 100 }
+                See gestures.rs.
+        auto_profile
+                Switch profiles by the focused window or running game.
+                    "auto_profile": { "rules": [{ "title": "exact:Genshin Impact", "profile": "Genshin" }] }
+                See window_watcher.rs.
+
+    Command line flags override the file:
+        --config <path>   read settings from another file
+        --port <selector> listen on this port only. Repeat it to build a priority list.
+        --list-ports      print the available MIDI input ports and exit
+        --list-sonar-devices
+                          print the audio devices Sonar can route channels to and exit
+###############################################################################*/
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub ports: Vec<String>,
+    pub devices: Vec<DeviceSettings>,
+    pub volume_updates_per_second: Option<f32>,
+    pub volume: VolumeSettings,
+    pub sonar: SonarSettings,
+    pub notifications: NotifierSettings,
+    pub osd: OsdSettings,
+    pub gestures: GestureSettings,
+    pub auto_profile: AutoProfileSettings,
+    #[serde(skip)]
+    pub list_ports: bool,
+    #[serde(skip)]
+    pub list_sonar_devices: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct DeviceSettings {
+    pub name: Option<String>,
+    pub ports: Vec<String>,
+    pub profile: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SonarSettings {
+    pub mode: SonarMode,
+    pub database: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SonarMode {
+    #[default]
+    Auto,
+    Classic,
+    Streamer,
+}
+
+/// Finds `name` (a file or folder that ships with MyMIDI) next to the executable,
+/// or in a folder above it, so `my_midi/target/release/my_midi.exe` finds
+/// `my_midi/mappings` wherever it was started from. Falls back to the
+/// working directory if there is none.
+pub fn app_path(name: &str) -> PathBuf {
+    let found = env::current_exe().ok().and_then(|exe| {
+        exe.ancestors().skip(1).map(|dir| dir.join(name)).find(|path| path.exists())
+    });
+    found.unwrap_or_else(|| PathBuf::from(name))
+}
+
+/// The settings file used when `--config` isn't given.
+pub fn default_settings_path() -> PathBuf {
+    app_path("settings.json")
+}
+
+impl Settings {
+    /// Reads the settings file and applies the command line flags on top of it.
+    pub fn from_args() -> Result<Self, Box<dyn Error>> {
+        let mut config_path = None;
+        let mut ports = Vec::new();
+        let mut list_ports = false;
+        let mut list_sonar_devices = false;
+
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => config_path = Some(PathBuf::from(args.next().ok_or("--config needs a path")?)),
+                "--port" => ports.push(args.next().ok_or("--port needs a port name, regex:<pattern> or index:<n>")?),
+                "--list-ports" => list_ports = true,
+                "--list-sonar-devices" => list_sonar_devices = true,
+                other => return Err(format!("Unknown argument '{}'", other).into()),
+            }
+        }
+
+        let mut settings = match config_path {
+            Some(path) if !path.exists() => return Err(format!("Settings file {} not found", path.display()).into()),
+            Some(path) => Settings::load(&path)?,
+            None => Settings::load(&default_settings_path())?,
+        };
+        if !ports.is_empty() {
+            settings.ports = ports;
+            settings.devices.clear();
+        }
+        settings.list_ports = list_ports;
+        settings.list_sonar_devices = list_sonar_devices;
+        Ok(settings)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if !path.exists() {
+            return Ok(Settings::default());
+        }
+        let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let settings = serde_json::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(settings)
+    }
+
+    /// The devices to listen on. Without a `devices` list, `ports` describes a single device.
+    pub fn devices(&self) -> Vec<DeviceSettings> {
+        if self.devices.is_empty() {
+            vec![DeviceSettings { ports: self.ports.clone(), ..DeviceSettings::default() }]
+        } else {
+            self.devices.clone()
+        }
+    }
+}
+
+impl SonarSettings {
+    /// Whether to talk to Sonar in Streamer Mode, reading the database if the mode is "auto".
+    pub fn streamer_mode(&self) -> bool {
+        match self.mode {
+            SonarMode::Classic => false,
+            SonarMode::Streamer => true,
+            SonarMode::Auto => {
+                let database = self.database.clone().unwrap_or_else(steelseries_sonar_api::default_database_path);
+                steelseries_sonar_api::detect_streamer_mode(&database)
+            },
+        }
+    }
+}
+
+impl DeviceSettings {
+    pub fn port_selectors(&self) -> Result<Vec<PortSelector>, String> {
+        self.ports.iter().map(|text| PortSelector::parse(text)).collect()
+    }
+}
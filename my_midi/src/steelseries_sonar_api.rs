@@ -1,12 +1,43 @@
-use reqwest::blocking::Client;
+use reqwest::blocking::{Client, Response};
+use reqwest::{Method, StatusCode};
 use serde::{Deserialize, Serialize};
 use std::env;
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read};
+use std::fmt;
 use std::path::PathBuf;
-use crate::midi_commands; 
-use rusqlite::{Connection, Result as SqlResult};
+use rusqlite::{Connection, OpenFlags, Result as SqlResult};
 use std::path::Path;
+use thiserror::Error;
+
+/// Everything that can go wrong talking to Sonar. Callers can match on it to decide
+/// whether to retry, tell the user, or carry on.
+#[derive(Debug, Error)]
+pub enum SonarError {
+    /// SteelSeries GG's coreProps.json is missing, so GG probably isn't installed.
+    #[error("SteelSeries GG config not found at {}", path.display())]
+    ConfigNotFound { path: PathBuf, source: io::Error },
+    #[error("Could not read SteelSeries GG config: {0}")]
+    BadCoreProps(#[from] serde_json::Error),
+    /// GG didn't tell us where Sonar's web server is. GG or Sonar is likely not running.
+    #[error("Could not find Sonar's web server. Is SteelSeries GG running? ({0})")]
+    SubAppsUnavailable(String),
+    #[error("Sonar has no channel called '{0}'")]
+    ChannelNotFound(String),
+    #[error("Sonar has no audio device called '{0}'")]
+    DeviceNotFound(String),
+    #[error("Volume {0} is outside 0.0 to 1.0")]
+    InvalidVolume(f32),
+    #[error("Chat mix balance {0} is outside -1.0 to 1.0")]
+    InvalidBalance(f32),
+    #[error("Sonar answered with HTTP {0}")]
+    HttpStatus(u16),
+    /// Sonar answered, but not with what we expected. Probably a newer Sonar version.
+    #[error("Unexpected answer from Sonar: {0}")]
+    UnexpectedResponse(String),
+    #[error("Could not reach Sonar: {0}")]
+    Transport(#[from] reqwest::Error),
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 struct CommonAppData {
@@ -37,29 +68,98 @@ struct SonarMetadata {
     web_server_address: String,
 }
 
+/// The two mixes Sonar keeps in Streamer Mode: what you hear (monitoring) and
+/// what your stream hears (streaming). Classic mode only has one mix.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mix {
+    #[default]
+    Monitoring,
+    Streaming,
+}
+
+impl fmt::Display for Mix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mix::Monitoring => write!(f, "monitoring"),
+            Mix::Streaming => write!(f, "streaming"),
+        }
+    }
+}
+
+/// Volume and mute of one channel, as Sonar reports them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelState {
+    pub volume: f32,
+    pub muted: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChannelStateResponse {
+    volume: f32,
+    muted: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatMixResponse {
+    balance: f32,
+}
+
+/// An output (render) or input (capture) device Sonar can route a channel to.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct AudioDevice {
+    pub id: String,
+    #[serde(rename = "friendlyName")]
+    pub name: String,
+    #[serde(rename = "dataFlow")]
+    pub flow: DataFlow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DataFlow {
+    Render,
+    Capture,
+}
+
+const CHANNELS: [&str; 6] = ["master", "game", "chatRender", "media", "aux", "chatCapture"];
+
 #[derive(Debug)]
 pub struct Sonar {
     streamer_mode: bool,
-    volume_path: String,
     base_url: String,
+    /// Where Sonar's own web server listens. Looked up from /subApps the first time
+    /// it's needed and again only when a request to it fails. Empty until then.
     web_server_address: String,
+    /// Reused for every request so each knob turn doesn't set up a new connection.
+    client: Client,
 }
 
 /*###############################################################################
-Currently, SteelSeries Sonar Streamer Mode is not functional, but it is a work in progress. 
-This is the code to extract the mode from the SteelSeries database.
+Sonar runs in either Classic or Streamer Mode, and the two use different volume
+endpoints. The mode is stored in Sonar's SQLite database, so we read it from there.
 If you aren't using SteelSeries Sonar for multi-channel audio, you can remove this safely
 as well as any SteelSeries code in the main() function.
 ###############################################################################*/
-pub fn fetch_streamer_mode() -> SqlResult<bool> {
-    let db_path = Path::new("C:\\ProgramData\\SteelSeries\\GG\\apps\\sonar\\db\\database.db");
-    let conn = Connection::open(db_path)?;
+pub fn default_database_path() -> PathBuf {
+    PathBuf::from(env::var("ProgramData").unwrap_or_else(|_| "C:\\ProgramData".to_string()))
+        .join("SteelSeries")
+        .join("GG")
+        .join("apps")
+        .join("sonar")
+        .join("db")
+        .join("database.db")
+}
+
+pub fn fetch_streamer_mode(db_path: &Path) -> SqlResult<bool> {
+    // Sonar has the database open too, so only read from it
+    let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
 
     let mut stmt = conn.prepare("SELECT value FROM key_value WHERE key = 'MODE'")?;
     let mut mode_iter = stmt.query_map([], |row| row.get::<_, String>(0))?;
 
     if let Some(mode) = mode_iter.next() {
-        // If there's a result, compare it (case-insensitively) to "streamer"
+        // If there's a result, compare it (case-insensitively) to "stream"
         Ok(mode?.to_lowercase() == "stream")
     } else {
         // If there's no result, default to false
@@ -67,24 +167,41 @@ pub fn fetch_streamer_mode() -> SqlResult<bool> {
     }
 }
 
-impl Sonar {
-    pub fn new(streamer_mode: bool, app_data_path: Option<PathBuf>) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut sonar = Sonar {
-            streamer_mode,
-            volume_path: if streamer_mode { "/volumeSettings/streamer/monitoring".to_string() } else { "/volumeSettings/classic".to_string() },
-            base_url: String::new(),
-            web_server_address: String::new(),
-        };
-
-        if streamer_mode {
-            sonar.volume_path = "/volumeSettings/streamer/monitoring".to_string();
-        }
+/// Like `fetch_streamer_mode`, but falls back to Classic mode if the database can't be read.
+pub fn detect_streamer_mode(db_path: &Path) -> bool {
+    match fetch_streamer_mode(db_path) {
+        Ok(streamer_mode) => streamer_mode,
+        Err(e) => {
+            println!("Could not read the Sonar mode from {} ({}). Assuming Classic mode.", db_path.display(), e);
+            false
+        },
+    }
+}
 
+impl Sonar {
+    pub fn new(streamer_mode: bool, app_data_path: Option<PathBuf>) -> Result<Self, SonarError> {
+        let mut sonar = Sonar::with_base_url(streamer_mode, String::new())?;
         sonar.load_base_url(app_data_path)?;
         Ok(sonar)
     }
 
-    pub fn load_base_url(&mut self, app_data_path: Option<PathBuf>) -> Result<(), Box<dyn std::error::Error>> {
+    /// Talks to the SteelSeries GG server at `base_url` instead of the one in coreProps.json.
+    pub fn with_base_url(streamer_mode: bool, base_url: String) -> Result<Self, SonarError> {
+        // GG uses a self-signed certificate, and it always runs on this machine,
+        // so skip any proxy from the environment.
+        let client = Client::builder()
+            .danger_accept_invalid_certs(true)
+            .no_proxy()
+            .build()?;
+        Ok(Sonar {
+            streamer_mode,
+            base_url,
+            web_server_address: String::new(),
+            client,
+        })
+    }
+
+    pub fn load_base_url(&mut self, app_data_path: Option<PathBuf>) -> Result<(), SonarError> {
         let path = app_data_path.unwrap_or_else(|| {
             PathBuf::from(env::var("ProgramData").unwrap_or_default())
                 .join("SteelSeries")
@@ -92,117 +209,585 @@ impl Sonar {
                 .join("coreProps.json")
         });
 
-        let mut file = File::open(path)?;
         let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
+        File::open(&path)
+            .and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|source| SonarError::ConfigNotFound { path, source })?;
         let common_app_data: CommonAppData = serde_json::from_str(&contents)?;
 
         self.base_url = format!("https://{}", common_app_data.gg_encrypted_address);
         Ok(())
     }
 
-    pub fn update_web_server_address(&mut self, body: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let parsed: SubAppsResponse = serde_json::from_str(body)?;
+    pub fn update_web_server_address(&mut self, body: &str) -> Result<(), SonarError> {
+        let parsed: SubAppsResponse = serde_json::from_str(body)
+            .map_err(|e| SonarError::SubAppsUnavailable(format!("unexpected /subApps response: {}", e)))?;
         self.web_server_address = parsed.sub_apps.sonar.metadata.web_server_address;
         Ok(())
     }
 
-    pub fn set_volume(&self, channel: &str, volume: f32) -> Result<(), Box<dyn std::error::Error>> {
-        if !["master", "game", "chatRender", "media", "aux", "chatCapture"].contains(&channel) {
-            return Err("ChannelNotFoundError".into());
-        }
+    pub fn streamer_mode(&self) -> bool {
+        self.streamer_mode
+    }
+
+    /// Sets the volume of `channel` in the monitoring mix (or the only mix, in Classic mode).
+    pub fn set_volume(&mut self, channel: &str, volume: f32) -> Result<(), SonarError> {
+        self.set_mix_volume(channel, Mix::Monitoring, volume)
+    }
 
-        if volume < 0.0 || volume > 1.0 {
-            return Err("InvalidVolumeError".into());
+    /// Sets the volume of `channel` in `mix`. Classic mode has a single mix, so `mix`
+    /// only matters in Streamer Mode.
+    pub fn set_mix_volume(&mut self, channel: &str, mix: Mix, volume: f32) -> Result<(), SonarError> {
+        check_channel(channel)?;
+        if !(0.0..=1.0).contains(&volume) {
+            return Err(SonarError::InvalidVolume(volume));
         }
 
-        let client = Client::builder().danger_accept_invalid_certs(true).build()?;
-        let url = format!("{}{}/{}/Volume/{}", self.web_server_address, self.volume_path, channel, volume);
-        //let url = format!("{}/{}{}/{}/Volume", self.web_server_address, self.volume_path, channel, volume);
-        let response = client.put(&url).send()?;
+        let path = if self.streamer_mode {
+            format!("/volumeSettings/streamer/{}/{}/volume/{}", mix, channel, volume)
+        } else {
+            format!("/volumeSettings/classic/{}/Volume/{}", channel, volume)
+        };
+        self.request(Method::PUT, &path)?;
+        Ok(())
+    }
+
+    /// The current volume of `channel` in the monitoring mix (or the only mix, in Classic mode).
+    pub fn get_volume(&mut self, channel: &str) -> Result<f32, SonarError> {
+        Ok(self.channel_state(channel, Mix::Monitoring)?.volume)
+    }
+
+    /// Reads the volume and mute state of `channel` in `mix` back from Sonar.
+    pub fn channel_state(&mut self, channel: &str, mix: Mix) -> Result<ChannelState, SonarError> {
+        check_channel(channel)?;
+        let settings = self.volume_settings()?;
+        self.parse_channel_state(&settings, channel, mix)
+    }
 
-        if response.status() != reqwest::StatusCode::OK {
-            return Err("ServerNotAccessibleError".into());
-        }        
+    /// The state of every channel in `mix`, read with a single request.
+    pub fn all_channel_states(&mut self, mix: Mix) -> Result<Vec<(&'static str, ChannelState)>, SonarError> {
+        let settings = self.volume_settings()?;
+        CHANNELS.iter()
+            .map(|channel| Ok((*channel, self.parse_channel_state(&settings, channel, mix)?)))
+            .collect()
+    }
 
+    pub fn set_mute(&mut self, channel: &str, mix: Mix, muted: bool) -> Result<(), SonarError> {
+        check_channel(channel)?;
+        let path = if self.streamer_mode {
+            format!("/volumeSettings/streamer/{}/{}/isMuted/{}", mix, channel, muted)
+        } else {
+            format!("/volumeSettings/classic/{}/Mute/{}", channel, muted)
+        };
+        self.request(Method::PUT, &path)?;
         Ok(())
     }
 
-    pub fn set_volume_for_channel(&mut self, channel: &str, volume: f32) {
-        // First, ensure the web server address is up-to-date
-        if let Err(e) = self.update_web_server_address_from_sub_apps() {
-            midi_commands::show_toast("Volume Control Failed", &format!("Failed to update web server address: {}", e));
-            return;
-        }
-        
-        if !["master", "game", "chatRender", "media", "aux", "chatCapture"].contains(&channel) {
-            midi_commands::show_toast("Volume Control Failed", "Channel not found");
-            return;
-        }
-    
-        if volume < 0.0 || volume > 1.0 {
-            midi_commands::show_toast("Volume Control Failed", "Invalid volume");
-            return;
+    /// Flips the mute state of `channel` in `mix` and returns whether it is muted now.
+    pub fn toggle_mute(&mut self, channel: &str, mix: Mix) -> Result<bool, SonarError> {
+        let muted = !self.channel_state(channel, mix)?.muted;
+        self.set_mute(channel, mix, muted)?;
+        Ok(muted)
+    }
+
+    /// The chat mix balance, from -1.0 (all game) to 1.0 (all chat).
+    pub fn get_chat_mix(&mut self) -> Result<f32, SonarError> {
+        let body = self.request(Method::GET, "/chatMix")?.text()?;
+        let parsed: ChatMixResponse = serde_json::from_str(&body)
+            .map_err(|e| SonarError::UnexpectedResponse(format!("chat mix: {}", e)))?;
+        Ok(parsed.balance)
+    }
+
+    /// Sets the chat mix balance, from -1.0 (all game) to 1.0 (all chat).
+    pub fn set_chat_mix(&mut self, balance: f32) -> Result<(), SonarError> {
+        if !(-1.0..=1.0).contains(&balance) {
+            return Err(SonarError::InvalidBalance(balance));
         }
-    
-        let client = match Client::builder().danger_accept_invalid_certs(true).build() {
-            Ok(client) => client,
-            Err(e) => {
-                midi_commands::show_toast("Volume Control Failed", &format!("Failed to build client: {}", e));
-                return;
-            },
+        self.request(Method::PUT, &format!("/chatMix?balance={}", balance))?;
+        Ok(())
+    }
+
+    /// Every audio device Sonar knows about, outputs and inputs.
+    pub fn audio_devices(&mut self) -> Result<Vec<AudioDevice>, SonarError> {
+        let body = self.request(Method::GET, "/audioDevices")?.text()?;
+        serde_json::from_str(&body).map_err(|e| SonarError::UnexpectedResponse(format!("audio devices: {}", e)))
+    }
+
+    /// The devices `channel` can be routed to: inputs for the microphone
+    /// (chatCapture), outputs for everything else.
+    pub fn devices_for_channel(&mut self, channel: &str) -> Result<Vec<AudioDevice>, SonarError> {
+        check_channel(channel)?;
+        let flow = channel_flow(channel);
+        Ok(self.audio_devices()?.into_iter().filter(|device| device.flow == flow).collect())
+    }
+
+    /// Sends `channel` to the device with id `device_id`. In Streamer Mode, Sonar
+    /// routes whole mixes rather than channels, so `mix` is routed instead
+    /// (or the microphone, for chatCapture).
+    pub fn route_channel(&mut self, channel: &str, mix: Mix, device_id: &str) -> Result<(), SonarError> {
+        check_channel(channel)?;
+        let path = if !self.streamer_mode {
+            format!("/classicRedirections/{}/deviceId/{}", channel, device_id)
+        } else if channel == "chatCapture" {
+            format!("/streamRedirections/mic/deviceId/{}", device_id)
+        } else {
+            format!("/streamRedirections/{}/deviceId/{}", mix, device_id)
         };
-    
-        let url = format!("{}{}/{}/Volume/{}", self.web_server_address, self.volume_path, channel, volume);
-        let response = match client.put(&url).send() {
-            Ok(response) => response,
-            Err(e) => {
-                midi_commands::show_toast("Volume Control Failed", &format!("Failed to send request: {}", e));
-                return;
+        self.request(Method::PUT, &path)?;
+        Ok(())
+    }
+
+    /// Like `route_channel`, but picks the device by name. `device` is either a
+    /// device id or part of the device's name, ignoring case, e.g. "speakers".
+    /// Returns the device it picked.
+    pub fn route_channel_to(&mut self, channel: &str, mix: Mix, device: &str) -> Result<AudioDevice, SonarError> {
+        let wanted = device.to_lowercase();
+        let found = self.devices_for_channel(channel)?.into_iter()
+            .find(|candidate| candidate.id == device || candidate.name.to_lowercase().contains(&wanted))
+            .ok_or_else(|| SonarError::DeviceNotFound(device.to_string()))?;
+        self.route_channel(channel, mix, &found.id)?;
+        Ok(found)
+    }
+
+    /// The raw volume settings JSON for the current mode.
+    fn volume_settings(&mut self) -> Result<serde_json::Value, SonarError> {
+        let path = if self.streamer_mode { "/volumeSettings/streamer" } else { "/volumeSettings/classic" };
+        let body = self.request(Method::GET, path)?.text()?;
+        serde_json::from_str(&body).map_err(|e| SonarError::UnexpectedResponse(format!("volume settings: {}", e)))
+    }
+
+    /// Finds `channel` in the volume settings. They look like
+    ///     { "masters": { "classic": { "volume": 1, "muted": false },
+    ///                    "stream": { "monitoring": {...}, "streaming": {...} } },
+    ///       "devices": { "game": { "classic": {...}, "stream": { "monitoring": {...}, ... } }, ... } }
+    fn parse_channel_state(&self, settings: &serde_json::Value, channel: &str, mix: Mix) -> Result<ChannelState, SonarError> {
+        let entry = if channel == "master" { &settings["masters"] } else { &settings["devices"][channel] };
+        let state = if self.streamer_mode { &entry["stream"][mix.to_string()] } else { &entry["classic"] };
+        let parsed: ChannelStateResponse = serde_json::from_value(state.clone())
+            .map_err(|e| SonarError::UnexpectedResponse(format!("{} volume: {}", channel, e)))?;
+        Ok(ChannelState { volume: parsed.volume, muted: parsed.muted })
+    }
+
+    /// Sends a request to Sonar's web server. If the server can't be reached or doesn't
+    /// know the path, Sonar has probably restarted on a new port, so the address is
+    /// looked up again and the request is retried once. Anything but 200 OK is an error.
+    fn request(&mut self, method: Method, path: &str) -> Result<Response, SonarError> {
+        if self.web_server_address.is_empty() {
+            self.update_web_server_address_from_sub_apps()?;
+        }
+
+        let response = match self.client.request(method.clone(), format!("{}{}", self.web_server_address, path)).send() {
+            Ok(response) if response.status() != StatusCode::NOT_FOUND => response,
+            Err(e) if !e.is_connect() => return Err(e.into()),
+            _ => {
+                self.update_web_server_address_from_sub_apps()?;
+                self.client.request(method, format!("{}{}", self.web_server_address, path)).send()?
             },
         };
-    
-        if response.status() != reqwest::StatusCode::OK {
-            midi_commands::show_toast("Volume Control Failed", "Server not accessible");
+
+        if response.status() != StatusCode::OK {
+            return Err(SonarError::HttpStatus(response.status().as_u16()));
         }
+        Ok(response)
     }
-    
-    
+
     /// Fetches the /subApps response and updates the web server address.
-    fn update_web_server_address_from_sub_apps(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let client = Client::builder().danger_accept_invalid_certs(true).build()?;
+    fn update_web_server_address_from_sub_apps(&mut self) -> Result<(), SonarError> {
         let sub_apps_url = format!("{}{}", self.base_url, "/subApps");
-        let response_body = client.get(&sub_apps_url).send()?.text()?;
-        
-        // Assuming the SubAppsResponse and related structs are correctly defined to match the JSON response structure
-        let parsed: SubAppsResponse = serde_json::from_str(&response_body)?;
-        self.web_server_address = parsed.sub_apps.sonar.metadata.web_server_address;
-        
+        let response_body = self.client.get(&sub_apps_url).send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.text())
+            .map_err(|e| SonarError::SubAppsUnavailable(e.to_string()))?;
+        self.update_web_server_address(&response_body)
+    }
+}
+
+fn channel_flow(channel: &str) -> DataFlow {
+    if channel == "chatCapture" { DataFlow::Capture } else { DataFlow::Render }
+}
+
+fn check_channel(channel: &str) -> Result<(), SonarError> {
+    if CHANNELS.contains(&channel) {
         Ok(())
+    } else {
+        Err(SonarError::ChannelNotFound(channel.to_string()))
     }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut sonar = Sonar::new(false, None)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
 
-    // Assuming `danger_accept_invalid_certs(true)` is for local development only.
-    let client = Client::builder()
-        .danger_accept_invalid_certs(true)
-        .build()?;
+    /// A tiny HTTP server on a random local port. `respond` gets the method and path
+    /// of each request and returns the status code and body to send back.
+    struct MockServer {
+        address: String,
+        requests: Arc<Mutex<Vec<String>>>,
+    }
 
-    // Fetch and parse the /subApps response to get the web server address.
-    let sub_apps_url = format!("{}{}", sonar.base_url, "/subApps");
-    let response_body = client.get(&sub_apps_url).send()?.text()?;
-    println!("full response body: {}",response_body);
-    sonar.update_web_server_address(&response_body)?;
+    impl MockServer {
+        fn start(respond: impl Fn(&str, &str) -> (u16, String) + Send + 'static) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let requests_for_thread = requests.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = match stream {
+                        Ok(stream) => stream,
+                        Err(_) => break,
+                    };
+                    // None of our requests have a body, so the headers are all there is
+                    let mut request = Vec::new();
+                    let mut buffer = [0; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut buffer) {
+                            Ok(0) | Err(_) => break,
+                            Ok(read) => request.extend_from_slice(&buffer[..read]),
+                        }
+                    }
+                    let request = String::from_utf8_lossy(&request);
+                    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+                    let method = request_line.next().unwrap_or_default().to_string();
+                    let path = request_line.next().unwrap_or_default().to_string();
+                    requests_for_thread.lock().unwrap().push(format!("{} {}", method, path));
 
-    // Now, set the volume for a specific channel to 50%
-    // The channel name should be one of the ones you've defined: ["master", "game", "chatRender", "media", "aux", "chatCapture"]
-    // Volume is a float, so 50% is represented as 0.5
-    sonar.set_volume("master", 0.5)?;
+                    let (status, body) = respond(&method, &path);
+                    let _ = write!(stream, "HTTP/1.1 {} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body);
+                }
+            });
+            MockServer { address, requests }
+        }
 
-    println!("Volume set successfully.");
+        fn requests(&self) -> Vec<String> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
 
-    Ok(())
-}
+    fn sub_apps_body(web_server_address: &str) -> String {
+        format!(r#"{{"subApps":{{"sonar":{{"metadata":{{"webServerAddress":"{}"}}}}}}}}"#, web_server_address)
+    }
+
+    /// An address nothing is listening on.
+    fn closed_address() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    }
+
+    fn sonar_server() -> MockServer {
+        MockServer::start(|method, _path| match method {
+            "PUT" => (200, String::new()),
+            _ => (405, String::new()),
+        })
+    }
+
+    #[test]
+    fn looks_up_the_address_once() {
+        let sonar_server = sonar_server();
+        let address = sonar_server.address.clone();
+        let gg = MockServer::start(move |_, _| (200, sub_apps_body(&address)));
+
+        let mut sonar = Sonar::with_base_url(false, gg.address.clone()).unwrap();
+        sonar.set_volume("master", 0.5).unwrap();
+        sonar.set_volume("game", 0.25).unwrap();
+        sonar.set_volume("media", 1.0).unwrap();
+
+        assert_eq!(gg.requests(), vec!["GET /subApps"]);
+        assert_eq!(sonar_server.requests(), vec![
+            "PUT /volumeSettings/classic/master/Volume/0.5",
+            "PUT /volumeSettings/classic/game/Volume/0.25",
+            "PUT /volumeSettings/classic/media/Volume/1",
+        ]);
+    }
+
+    #[test]
+    fn refreshes_the_address_when_sonar_moved() {
+        // The first lookup hands out a port Sonar no longer listens on
+        let sonar_server = sonar_server();
+        let addresses = Arc::new(Mutex::new(vec![sonar_server.address.clone(), closed_address()]));
+        let gg = MockServer::start(move |_, _| (200, sub_apps_body(&addresses.lock().unwrap().pop().unwrap())));
+
+        let mut sonar = Sonar::with_base_url(false, gg.address.clone()).unwrap();
+        sonar.set_volume("master", 0.5).unwrap();
+        sonar.set_volume("master", 0.75).unwrap();
+
+        assert_eq!(gg.requests(), vec!["GET /subApps", "GET /subApps"]);
+        assert_eq!(sonar_server.requests(), vec![
+            "PUT /volumeSettings/classic/master/Volume/0.5",
+            "PUT /volumeSettings/classic/master/Volume/0.75",
+        ]);
+    }
+
+    #[test]
+    fn refreshes_the_address_on_not_found() {
+        let stale_server = MockServer::start(|_, _| (404, String::new()));
+        let sonar_server = sonar_server();
+        let addresses = Arc::new(Mutex::new(vec![sonar_server.address.clone(), stale_server.address.clone()]));
+        let gg = MockServer::start(move |_, _| (200, sub_apps_body(&addresses.lock().unwrap().pop().unwrap())));
+
+        let mut sonar = Sonar::with_base_url(false, gg.address.clone()).unwrap();
+        sonar.set_volume("chatRender", 0.5).unwrap();
+
+        assert_eq!(stale_server.requests(), vec!["PUT /volumeSettings/classic/chatRender/Volume/0.5"]);
+        assert_eq!(sonar_server.requests(), vec!["PUT /volumeSettings/classic/chatRender/Volume/0.5"]);
+    }
+
+    #[test]
+    fn retries_only_once() {
+        let stale_server = MockServer::start(|_, _| (404, String::new()));
+        let address = stale_server.address.clone();
+        let gg = MockServer::start(move |_, _| (200, sub_apps_body(&address)));
+
+        let mut sonar = Sonar::with_base_url(false, gg.address.clone()).unwrap();
+        assert!(matches!(sonar.set_volume("master", 0.5), Err(SonarError::HttpStatus(404))));
 
+        assert_eq!(gg.requests().len(), 2);
+        assert_eq!(stale_server.requests().len(), 2);
+    }
+
+    #[test]
+    fn does_not_retry_other_failures() {
+        let failing_server = MockServer::start(|_, _| (500, String::new()));
+        let address = failing_server.address.clone();
+        let gg = MockServer::start(move |_, _| (200, sub_apps_body(&address)));
+
+        let mut sonar = Sonar::with_base_url(false, gg.address.clone()).unwrap();
+        assert!(matches!(sonar.set_volume("master", 0.5), Err(SonarError::HttpStatus(500))));
+
+        assert_eq!(gg.requests().len(), 1);
+        assert_eq!(failing_server.requests().len(), 1);
+    }
+
+    #[test]
+    fn streamer_mode_uses_the_chosen_mix() {
+        let sonar_server = sonar_server();
+        let address = sonar_server.address.clone();
+        let gg = MockServer::start(move |_, _| (200, sub_apps_body(&address)));
+
+        let mut sonar = Sonar::with_base_url(true, gg.address.clone()).unwrap();
+        sonar.set_volume("game", 0.5).unwrap();
+        sonar.set_mix_volume("game", Mix::Streaming, 0.25).unwrap();
+
+        let mut classic = Sonar::with_base_url(false, gg.address.clone()).unwrap();
+        classic.set_mix_volume("game", Mix::Streaming, 1.0).unwrap();
+
+        assert_eq!(sonar_server.requests(), vec![
+            "PUT /volumeSettings/streamer/monitoring/game/volume/0.5",
+            "PUT /volumeSettings/streamer/streaming/game/volume/0.25",
+            "PUT /volumeSettings/classic/game/Volume/1",
+        ]);
+    }
+
+    const CLASSIC_SETTINGS: &str = r#"{
+        "masters": { "classic": { "volume": 0.8, "muted": false } },
+        "devices": {
+            "game": { "classic": { "volume": 0.5, "muted": true } },
+            "chatRender": { "classic": { "volume": 0.25, "muted": false } },
+            "media": { "classic": { "volume": 1, "muted": false } },
+            "aux": { "classic": { "volume": 1, "muted": false } },
+            "chatCapture": { "classic": { "volume": 1, "muted": false } }
+        }
+    }"#;
+
+    const STREAMER_SETTINGS: &str = r#"{
+        "masters": { "stream": {
+            "monitoring": { "volume": 0.9, "muted": false },
+            "streaming": { "volume": 0.7, "muted": false }
+        } },
+        "devices": {
+            "game": { "stream": {
+                "monitoring": { "volume": 0.6, "muted": false },
+                "streaming": { "volume": 0.3, "muted": true }
+            } }
+        }
+    }"#;
+
+    /// A Sonar that answers GETs from the settings above and accepts every PUT.
+    fn sonar_with_settings() -> MockServer {
+        MockServer::start(|method, path| match (method, path) {
+            ("GET", "/volumeSettings/classic") => (200, CLASSIC_SETTINGS.to_string()),
+            ("GET", "/volumeSettings/streamer") => (200, STREAMER_SETTINGS.to_string()),
+            ("GET", "/chatMix") => (200, r#"{"balance": -0.5, "state": "enabled"}"#.to_string()),
+            ("PUT", _) => (200, String::new()),
+            _ => (404, String::new()),
+        })
+    }
+
+    fn connect(streamer_mode: bool, sonar_server: &MockServer) -> (Sonar, MockServer) {
+        let address = sonar_server.address.clone();
+        let gg = MockServer::start(move |_, _| (200, sub_apps_body(&address)));
+        (Sonar::with_base_url(streamer_mode, gg.address.clone()).unwrap(), gg)
+    }
+
+    #[test]
+    fn reads_classic_volume_settings() {
+        let sonar_server = sonar_with_settings();
+        let (mut sonar, _gg) = connect(false, &sonar_server);
+
+        assert_eq!(sonar.get_volume("master").unwrap(), 0.8);
+        assert_eq!(sonar.channel_state("game", Mix::Monitoring).unwrap(), ChannelState { volume: 0.5, muted: true });
+        let states = sonar.all_channel_states(Mix::Monitoring).unwrap();
+        assert_eq!(states.len(), CHANNELS.len());
+        assert_eq!(states[2], ("chatRender", ChannelState { volume: 0.25, muted: false }));
+    }
+
+    #[test]
+    fn reads_streamer_volume_settings() {
+        let sonar_server = sonar_with_settings();
+        let (mut sonar, _gg) = connect(true, &sonar_server);
+
+        assert_eq!(sonar.get_volume("master").unwrap(), 0.9);
+        assert_eq!(sonar.channel_state("master", Mix::Streaming).unwrap().volume, 0.7);
+        assert_eq!(sonar.channel_state("game", Mix::Streaming).unwrap(), ChannelState { volume: 0.3, muted: true });
+        // Missing from the settings above
+        assert!(matches!(sonar.channel_state("media", Mix::Monitoring), Err(SonarError::UnexpectedResponse(_))));
+    }
+
+    #[test]
+    fn toggles_mute() {
+        let sonar_server = sonar_with_settings();
+        let (mut sonar, _gg) = connect(false, &sonar_server);
+        assert!(!sonar.toggle_mute("game", Mix::Monitoring).unwrap());
+        assert!(sonar.toggle_mute("chatRender", Mix::Monitoring).unwrap());
+
+        let streamer_server = sonar_with_settings();
+        let (mut streamer, _gg) = connect(true, &streamer_server);
+        streamer.set_mute("game", Mix::Streaming, true).unwrap();
+
+        assert_eq!(sonar_server.requests(), vec![
+            "GET /volumeSettings/classic",
+            "PUT /volumeSettings/classic/game/Mute/false",
+            "GET /volumeSettings/classic",
+            "PUT /volumeSettings/classic/chatRender/Mute/true",
+        ]);
+        assert_eq!(streamer_server.requests(), vec!["PUT /volumeSettings/streamer/streaming/game/isMuted/true"]);
+    }
+
+    #[test]
+    fn reads_and_sets_the_chat_mix() {
+        let sonar_server = sonar_with_settings();
+        let (mut sonar, _gg) = connect(false, &sonar_server);
+
+        assert_eq!(sonar.get_chat_mix().unwrap(), -0.5);
+        sonar.set_chat_mix(0.25).unwrap();
+        assert!(matches!(sonar.set_chat_mix(2.0), Err(SonarError::InvalidBalance(_))));
+
+        assert_eq!(sonar_server.requests(), vec!["GET /chatMix", "PUT /chatMix?balance=0.25"]);
+    }
+
+    const AUDIO_DEVICES: &str = r#"[
+        { "id": "{0.0.0.00000000}.{headset}", "friendlyName": "Headset Earphone (Arctis Nova 7)", "dataFlow": "render" },
+        { "id": "{0.0.0.00000000}.{speakers}", "friendlyName": "Speakers (Realtek(R) Audio)", "dataFlow": "render" },
+        { "id": "{0.0.1.00000000}.{mic}", "friendlyName": "Microphone (Arctis Nova 7)", "dataFlow": "capture" }
+    ]"#;
+
+    fn sonar_with_devices() -> MockServer {
+        MockServer::start(|method, path| match (method, path) {
+            ("GET", "/audioDevices") => (200, AUDIO_DEVICES.to_string()),
+            ("PUT", _) => (200, String::new()),
+            _ => (404, String::new()),
+        })
+    }
+
+    #[test]
+    fn lists_devices_per_channel() {
+        let sonar_server = sonar_with_devices();
+        let (mut sonar, _gg) = connect(false, &sonar_server);
+
+        assert_eq!(sonar.audio_devices().unwrap().len(), 3);
+        let outputs: Vec<String> = sonar.devices_for_channel("game").unwrap().into_iter().map(|device| device.name).collect();
+        assert_eq!(outputs, vec!["Headset Earphone (Arctis Nova 7)", "Speakers (Realtek(R) Audio)"]);
+        let inputs = sonar.devices_for_channel("chatCapture").unwrap();
+        assert_eq!(inputs.len(), 1);
+        assert_eq!(inputs[0].flow, DataFlow::Capture);
+    }
+
+    #[test]
+    fn routes_a_channel_by_device_name() {
+        let sonar_server = sonar_with_devices();
+        let (mut sonar, _gg) = connect(false, &sonar_server);
+
+        let device = sonar.route_channel_to("game", Mix::Monitoring, "speakers").unwrap();
+        assert_eq!(device.id, "{0.0.0.00000000}.{speakers}");
+        // Only inputs are considered for the microphone
+        assert!(matches!(sonar.route_channel_to("chatCapture", Mix::Monitoring, "speakers"), Err(SonarError::DeviceNotFound(_))));
+        sonar.route_channel_to("chatCapture", Mix::Monitoring, "{0.0.1.00000000}.{mic}").unwrap();
+
+        assert_eq!(sonar_server.requests(), vec![
+            "GET /audioDevices",
+            "PUT /classicRedirections/game/deviceId/%7B0.0.0.00000000%7D.%7Bspeakers%7D",
+            "GET /audioDevices",
+            "GET /audioDevices",
+            "PUT /classicRedirections/chatCapture/deviceId/%7B0.0.1.00000000%7D.%7Bmic%7D",
+        ]);
+    }
+
+    #[test]
+    fn streamer_mode_routes_mixes() {
+        let sonar_server = sonar_with_devices();
+        let (mut sonar, _gg) = connect(true, &sonar_server);
+
+        sonar.route_channel("game", Mix::Streaming, "speakers").unwrap();
+        sonar.route_channel("chatCapture", Mix::Monitoring, "mic").unwrap();
+
+        assert_eq!(sonar_server.requests(), vec![
+            "PUT /streamRedirections/streaming/deviceId/speakers",
+            "PUT /streamRedirections/mic/deviceId/mic",
+        ]);
+    }
+
+    /// Writes a database shaped like Sonar's with MODE set to `mode`.
+    fn fixture_database(name: &str, mode: Option<&str>) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("mymidi-sonar-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let conn = Connection::open(&path).unwrap();
+        conn.execute("CREATE TABLE key_value (key TEXT PRIMARY KEY, value TEXT)", []).unwrap();
+        if let Some(mode) = mode {
+            conn.execute("INSERT INTO key_value (key, value) VALUES ('MODE', ?1)", [mode]).unwrap();
+        }
+        path
+    }
+
+    #[test]
+    fn detects_the_mode_from_the_database() {
+        let stream = fixture_database("stream", Some("stream"));
+        let classic = fixture_database("classic", Some("classic"));
+        let unset = fixture_database("unset", None);
+
+        assert!(fetch_streamer_mode(&stream).unwrap());
+        assert!(!fetch_streamer_mode(&classic).unwrap());
+        assert!(!fetch_streamer_mode(&unset).unwrap());
+        assert!(fetch_streamer_mode(&std::env::temp_dir().join("mymidi-no-such.db")).is_err());
+        assert!(!detect_streamer_mode(&std::env::temp_dir().join("mymidi-no-such.db")));
+
+        for path in [stream, classic, unset] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    fn reports_when_gg_is_not_running() {
+        let mut sonar = Sonar::with_base_url(false, closed_address()).unwrap();
+        assert!(matches!(sonar.set_volume("master", 0.5), Err(SonarError::SubAppsUnavailable(_))));
+    }
+
+    #[test]
+    fn reports_a_missing_config() {
+        let path = std::env::temp_dir().join("mymidi-no-such-dir").join("coreProps.json");
+        assert!(matches!(Sonar::new(false, Some(path)), Err(SonarError::ConfigNotFound { .. })));
+    }
+
+    #[test]
+    fn rejects_bad_input_without_a_request() {
+        let gg = MockServer::start(|_, _| (200, sub_apps_body("http://127.0.0.1:1")));
+        let mut sonar = Sonar::with_base_url(false, gg.address.clone()).unwrap();
+
+        assert!(matches!(sonar.set_volume("nope", 0.5), Err(SonarError::ChannelNotFound(channel)) if channel == "nope"));
+        assert!(matches!(sonar.set_volume("master", 1.5), Err(SonarError::InvalidVolume(_))));
+        assert!(gg.requests().is_empty());
+    }
+}
@@ -0,0 +1,182 @@
+// volume/mod.rs
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use serde::Deserialize;
+use crate::steelseries_sonar_api::{Mix, Sonar};
+
+pub mod pulse;
+pub mod sonar;
+pub mod system;
+
+/*###############################################################################
+Volume Backends
+    Knobs and mute pads talk to a `VolumeBackend` instead of to Sonar directly,
+    so MyMIDI works without SteelSeries. Pick one in settings.json:
+        "volume": { "backend": "pulse", "channels": { "game": "sink:alsa_output.usb-headset" } }
+    Backends:
+        sonar   SteelSeries Sonar (the default on Windows). Channels are Sonar's:
+                master, game, chatRender, media, aux, chatCapture. Prefix one with
+                "streaming/" for the streaming mix in Streamer Mode. Only Sonar
+                has a streaming mix; a "mix": "streaming" binding on any other
+                backend fails instead of sending it the prefixed name.
+        pulse   PulseAudio or PipeWire through `pactl` (the default on Linux).
+                See pulse.rs for channel names. "app:spotify" controls just
+                Spotify, so a knob can be bound to one application:
+                    "channels": { "music": "app:spotify", "browser": "app:firefox" }
+        system  The Windows default playback ("master") and recording ("mic") devices.
+        none    Volume bindings do nothing.
+    Mappings name logical channels ("game", "music", "voice", ...). `channels`
+    maps them to the backend's own names; a channel that isn't listed is passed
+    through as is, so Sonar's channel names work without any setup.
+
+    Adding a backend:
+        1. Create `src/volume/backend_name.rs` with a struct that implements `VolumeBackend`.
+        2. Add `pub mod backend_name;` above, a variant to `BackendKind` and a case in `Volume::new`.
+###############################################################################*/
+pub type VolumeResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+pub trait VolumeBackend: Send + Sync {
+    /// Shown in the console and in error toasts.
+    fn name(&self) -> &str;
+
+    /// Sets `channel` to `volume`, from 0.0 to 1.0.
+    fn set_volume(&self, channel: &str, volume: f32) -> VolumeResult<()>;
+
+    /// The current volume of `channel`, from 0.0 to 1.0.
+    fn get_volume(&self, channel: &str) -> VolumeResult<f32>;
+
+    fn set_mute(&self, channel: &str, muted: bool) -> VolumeResult<()>;
+
+    fn is_muted(&self, channel: &str) -> VolumeResult<bool>;
+
+    /// Whether channels can be in Sonar's streaming mix ("streaming/game").
+    fn has_streaming_mix(&self) -> bool {
+        false
+    }
+
+    /// Flips the mute state of `channel` and returns whether it is muted now.
+    fn toggle_mute(&self, channel: &str) -> VolumeResult<bool> {
+        let muted = !self.is_muted(channel)?;
+        self.set_mute(channel, muted)?;
+        Ok(muted)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendKind {
+    Sonar,
+    Pulse,
+    System,
+    None,
+}
+
+impl Default for BackendKind {
+    fn default() -> Self {
+        if cfg!(windows) {
+            BackendKind::Sonar
+        } else if cfg!(target_os = "linux") {
+            BackendKind::Pulse
+        } else {
+            BackendKind::None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct VolumeSettings {
+    pub backend: BackendKind,
+    /// Logical channel name -> the backend's channel name.
+    pub channels: HashMap<String, String>,
+}
+
+/// The chosen backend plus the logical channel names from settings.json.
+pub struct Volume {
+    backend: Box<dyn VolumeBackend>,
+    channels: HashMap<String, String>,
+}
+
+impl Volume {
+    /// `sonar` is only used by the Sonar backend. Without it, that backend is disabled.
+    pub fn new(settings: &VolumeSettings, sonar: Option<Arc<Mutex<Sonar>>>) -> Self {
+        let backend: Box<dyn VolumeBackend> = match (settings.backend, sonar) {
+            (BackendKind::Sonar, Some(sonar)) => Box::new(sonar::SonarBackend::new(sonar)),
+            (BackendKind::Sonar, None) => {
+                println!("SteelSeries Sonar isn't available, so volume bindings are disabled.");
+                Box::new(Disabled)
+            },
+            (BackendKind::Pulse, _) => Box::new(pulse::PulseBackend),
+            (BackendKind::System, _) => Box::new(system::SystemBackend),
+            (BackendKind::None, _) => Box::new(Disabled),
+        };
+        Volume { backend, channels: settings.channels.clone() }
+    }
+
+    pub fn backend_name(&self) -> &str {
+        self.backend.name()
+    }
+
+    /// The backend's name for the logical channel `channel`, in `mix` for Sonar.
+    /// Fails for the streaming mix on backends that don't have one.
+    pub fn resolve(&self, channel: &str, mix: Mix) -> VolumeResult<String> {
+        let channel = self.channels.get(channel).map(String::as_str).unwrap_or(channel);
+        match mix {
+            Mix::Monitoring => Ok(channel.to_string()),
+            Mix::Streaming if self.backend.has_streaming_mix() => Ok(format!("{}/{}", mix, channel)),
+            Mix::Streaming => Err(format!("{} has no streaming mix", self.backend.name()).into()),
+        }
+    }
+
+    pub fn set_volume(&self, channel: &str, mix: Mix, volume: f32) -> VolumeResult<()> {
+        self.backend.set_volume(&self.resolve(channel, mix)?, volume)
+    }
+
+    pub fn get_volume(&self, channel: &str, mix: Mix) -> VolumeResult<f32> {
+        self.backend.get_volume(&self.resolve(channel, mix)?)
+    }
+
+    pub fn toggle_mute(&self, channel: &str, mix: Mix) -> VolumeResult<bool> {
+        self.backend.toggle_mute(&self.resolve(channel, mix)?)
+    }
+}
+
+/// Used when there is no volume backend. Every call fails with a short explanation.
+struct Disabled;
+
+impl VolumeBackend for Disabled {
+    fn name(&self) -> &str {
+        "none"
+    }
+
+    fn set_volume(&self, _channel: &str, _volume: f32) -> VolumeResult<()> {
+        Err("volume control is disabled".into())
+    }
+
+    fn get_volume(&self, _channel: &str) -> VolumeResult<f32> {
+        Err("volume control is disabled".into())
+    }
+
+    fn set_mute(&self, _channel: &str, _muted: bool) -> VolumeResult<()> {
+        Err("volume control is disabled".into())
+    }
+
+    fn is_muted(&self, _channel: &str) -> VolumeResult<bool> {
+        Err("volume control is disabled".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_sonar_gets_the_streaming_mix() {
+        let settings = VolumeSettings { backend: BackendKind::Pulse, channels: HashMap::from([(String::from("music"), String::from("app:spotify"))]) };
+        let volume = Volume::new(&settings, None);
+        assert_eq!(volume.resolve("music", Mix::Monitoring).unwrap(), "app:spotify");
+        assert_eq!(volume.resolve("master", Mix::Monitoring).unwrap(), "master");
+        assert!(volume.resolve("music", Mix::Streaming).is_err());
+    }
+}
@@ -0,0 +1,291 @@
+// pulse.rs
+use std::collections::HashMap;
+use std::process::Command;
+use regex::Regex;
+use crate::volume::{VolumeBackend, VolumeResult};
+/*###############################################################################
+PulseAudio / PipeWire Backend
+    Runs `pactl`, which PipeWire also provides through pipewire-pulse.
+    Channels:
+        master              the default output
+        mic                 the default input
+        sink:<name>         an output, by the name `pactl list short sinks` shows
+        source:<name>       an input, by the name `pactl list short sources` shows
+        app:<name>          every stream an application is playing, e.g. "app:spotify".
+                            The stream's whole application name or process name has
+                            to be <name>, ignoring case, so "app:fire" doesn't pick
+                            up Firefox. "app:regex:<pattern>" matches either name
+                            against a regular expression instead. Streams are looked
+                            up on every change, so it keeps working when the app
+                            restarts. Setting the volume or mute of an app that
+                            isn't playing does nothing; reading it (as a mute pad
+                            does) fails with "isn't playing anything".
+    Any other name is taken as a sink name.
+###############################################################################*/
+pub struct PulseBackend;
+
+#[derive(Debug, PartialEq)]
+enum Device<'a> {
+    Sink(&'a str),
+    Source(&'a str),
+    App(&'a str),
+}
+
+fn device(channel: &str) -> Device<'_> {
+    match channel {
+        "master" => Device::Sink("@DEFAULT_SINK@"),
+        "mic" => Device::Source("@DEFAULT_SOURCE@"),
+        _ => if let Some(sink) = channel.strip_prefix("sink:") {
+            Device::Sink(sink)
+        } else if let Some(source) = channel.strip_prefix("source:") {
+            Device::Source(source)
+        } else if let Some(app) = channel.strip_prefix("app:") {
+            Device::App(app)
+        } else {
+            Device::Sink(channel)
+        },
+    }
+}
+
+/// Runs pactl with `args` and returns what it printed.
+fn run_pactl(args: &[&str]) -> VolumeResult<String> {
+    // The output is parsed, so it mustn't be translated
+    let output = Command::new("pactl").env("LC_ALL", "C").args(args).output()
+        .map_err(|e| format!("Could not run pactl: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("pactl: {}", String::from_utf8_lossy(&output.stderr).trim()).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Runs `pactl <command>-<sink|source|sink-input>-<what> <name> [value]` and returns what it printed.
+fn pactl(command: &str, what: &str, kind: &str, name: &str, value: Option<&str>) -> VolumeResult<String> {
+    let command = format!("{}-{}-{}", command, kind, what);
+    let mut args = vec![command.as_str(), name];
+    args.extend(value);
+    run_pactl(&args)
+}
+
+/// One application stream, from `pactl list sink-inputs`.
+#[derive(Debug, Default, PartialEq)]
+struct SinkInput {
+    id: String,
+    volume: Option<f32>,
+    muted: Option<bool>,
+    properties: HashMap<String, String>,
+}
+
+impl SinkInput {
+    /// Whether the stream belongs to the app `app` matches, going by its application or process name.
+    fn belongs_to(&self, app: &Regex) -> bool {
+        ["application.name", "application.process.binary"].iter()
+            .filter_map(|key| self.properties.get(*key))
+            .any(|name| app.is_match(name))
+    }
+}
+
+/// Splits `pactl list sink-inputs` into streams. Each one looks like
+///     Sink Input #42
+///         Mute: no
+///         Volume: front-left: 65536 / 100% / 0.00 dB,   front-right: ...
+///         Properties:
+///             application.name = "Spotify"
+///             application.process.binary = "spotify"
+fn parse_sink_inputs(output: &str) -> Vec<SinkInput> {
+    let mut inputs: Vec<SinkInput> = Vec::new();
+    for line in output.lines() {
+        let line = line.trim();
+        if let Some(id) = line.strip_prefix("Sink Input #") {
+            inputs.push(SinkInput { id: id.to_string(), ..SinkInput::default() });
+        } else if let Some(input) = inputs.last_mut() {
+            if line.starts_with("Volume:") && input.volume.is_none() {
+                input.volume = parse_volume(line);
+            } else if line.starts_with("Mute:") {
+                input.muted = parse_mute(line);
+            } else if let Some((key, value)) = line.split_once(" = ") {
+                input.properties.insert(key.to_string(), value.trim_matches('"').to_string());
+            }
+        }
+    }
+    inputs
+}
+
+/// What an "app:" channel's name matches: the whole name ignoring case, or a regex.
+fn app_pattern(app: &str) -> VolumeResult<Regex> {
+    let pattern = match app.strip_prefix("regex:") {
+        Some(pattern) => pattern.to_string(),
+        None => format!("(?i)^{}$", regex::escape(app)),
+    };
+    Regex::new(&pattern).map_err(|e| format!("Invalid app pattern '{}': {}", app, e).into())
+}
+
+/// The streams `app` is playing right now.
+fn app_streams(app: &str) -> VolumeResult<Vec<SinkInput>> {
+    let app = app_pattern(app)?;
+    let output = run_pactl(&["list", "sink-inputs"])?;
+    Ok(parse_sink_inputs(&output).into_iter().filter(|input| input.belongs_to(&app)).collect())
+}
+
+/// Like `app_streams`, but an app that isn't playing anything is an error.
+fn playing_app_streams(app: &str) -> VolumeResult<Vec<SinkInput>> {
+    let streams = app_streams(app)?;
+    if streams.is_empty() {
+        return Err(format!("{} isn't playing anything", app).into());
+    }
+    Ok(streams)
+}
+
+/// Reads the first channel's percentage out of `pactl get-sink-volume`, which looks like
+/// "Volume: front-left: 32768 /  50% / -18.06 dB,   front-right: 32768 /  50% / -18.06 dB".
+fn parse_volume(output: &str) -> Option<f32> {
+    output.split('/')
+        .find_map(|part| part.trim().strip_suffix('%'))
+        .and_then(|percent| percent.trim().parse::<f32>().ok())
+        .map(|percent| percent / 100.0)
+}
+
+/// `pactl get-sink-mute` prints "Mute: yes" or "Mute: no".
+fn parse_mute(output: &str) -> Option<bool> {
+    match output.trim().strip_prefix("Mute:")?.trim() {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
+
+impl VolumeBackend for PulseBackend {
+    fn name(&self) -> &str {
+        "PulseAudio"
+    }
+
+    fn set_volume(&self, channel: &str, volume: f32) -> VolumeResult<()> {
+        if !(0.0..=1.0).contains(&volume) {
+            return Err(format!("Volume {} is outside 0.0 to 1.0", volume).into());
+        }
+        let percent = format!("{}%", (volume * 100.0).round());
+        match device(channel) {
+            Device::Sink(name) => pactl("set", "volume", "sink", name, Some(&percent))?,
+            Device::Source(name) => pactl("set", "volume", "source", name, Some(&percent))?,
+            // Turning the knob while the app is quiet isn't worth a toast. Same for set_mute.
+            Device::App(app) => {
+                for stream in app_streams(app)? {
+                    pactl("set", "volume", "sink-input", &stream.id, Some(&percent))?;
+                }
+                return Ok(());
+            },
+        };
+        Ok(())
+    }
+
+    fn get_volume(&self, channel: &str) -> VolumeResult<f32> {
+        let output = match device(channel) {
+            Device::Sink(name) => pactl("get", "volume", "sink", name, None)?,
+            Device::Source(name) => pactl("get", "volume", "source", name, None)?,
+            Device::App(app) => {
+                let stream = playing_app_streams(app)?.remove(0);
+                return stream.volume.ok_or_else(|| format!("No volume for stream #{}", stream.id).into());
+            },
+        };
+        parse_volume(&output).ok_or_else(|| format!("Unexpected pactl output: {}", output.trim()).into())
+    }
+
+    fn set_mute(&self, channel: &str, muted: bool) -> VolumeResult<()> {
+        let muted = if muted { "1" } else { "0" };
+        match device(channel) {
+            Device::Sink(name) => pactl("set", "mute", "sink", name, Some(muted))?,
+            Device::Source(name) => pactl("set", "mute", "source", name, Some(muted))?,
+            Device::App(app) => {
+                for stream in app_streams(app)? {
+                    pactl("set", "mute", "sink-input", &stream.id, Some(muted))?;
+                }
+                return Ok(());
+            },
+        };
+        Ok(())
+    }
+
+    fn is_muted(&self, channel: &str) -> VolumeResult<bool> {
+        let output = match device(channel) {
+            Device::Sink(name) => pactl("get", "mute", "sink", name, None)?,
+            Device::Source(name) => pactl("get", "mute", "source", name, None)?,
+            // The app counts as muted only if all of its streams are
+            Device::App(app) => return Ok(playing_app_streams(app)?.iter().all(|stream| stream.muted == Some(true))),
+        };
+        parse_mute(&output).ok_or_else(|| format!("Unexpected pactl output: {}", output.trim()).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_channels() {
+        assert_eq!(device("master"), Device::Sink("@DEFAULT_SINK@"));
+        assert_eq!(device("mic"), Device::Source("@DEFAULT_SOURCE@"));
+        assert_eq!(device("sink:alsa_output.usb"), Device::Sink("alsa_output.usb"));
+        assert_eq!(device("source:alsa_input.usb"), Device::Source("alsa_input.usb"));
+        assert_eq!(device("app:spotify"), Device::App("spotify"));
+        assert_eq!(device("alsa_output.pci"), Device::Sink("alsa_output.pci"));
+    }
+
+    #[test]
+    fn parses_pactl_output() {
+        let volume = "Volume: front-left: 32768 /  50% / -18.06 dB,   front-right: 32768 /  50% / -18.06 dB\n        balance 0.00\n";
+        assert_eq!(parse_volume(volume), Some(0.5));
+        assert_eq!(parse_volume("Volume: mono: 65536 / 100% / 0.00 dB"), Some(1.0));
+        assert_eq!(parse_volume("nonsense"), None);
+        assert_eq!(parse_mute("Mute: yes\n"), Some(true));
+        assert_eq!(parse_mute("Mute: no"), Some(false));
+        assert_eq!(parse_mute("Volume: 50%"), None);
+    }
+
+    const SINK_INPUTS: &str = "Sink Input #42
+	Driver: protocol-native.c
+	Owner Module: 10
+	Client: 57
+	Sink: 1
+	Sample Specification: float32le 2ch 44100Hz
+	Mute: no
+	Volume: front-left: 32768 /  50% / -18.06 dB,   front-right: 32768 /  50% / -18.06 dB
+	        balance 0.00
+	Properties:
+		media.name = \"Spotify\"
+		application.name = \"Spotify\"
+		application.process.binary = \"spotify\"
+
+Sink Input #57
+	Mute: yes
+	Volume: front-left: 65536 / 100% / 0.00 dB,   front-right: 65536 / 100% / 0.00 dB
+	Properties:
+		application.name = \"Firefox\"
+		application.process.binary = \"firefox-bin\"
+";
+
+    #[test]
+    fn parses_sink_inputs() {
+        let inputs = parse_sink_inputs(SINK_INPUTS);
+        assert_eq!(inputs.len(), 2);
+        assert_eq!(inputs[0].id, "42");
+        assert_eq!(inputs[0].volume, Some(0.5));
+        assert_eq!(inputs[0].muted, Some(false));
+        assert_eq!(inputs[0].properties.get("application.name").map(String::as_str), Some("Spotify"));
+        assert_eq!(inputs[1].id, "57");
+        assert_eq!(inputs[1].muted, Some(true));
+    }
+
+    #[test]
+    fn matches_streams_by_app() {
+        let inputs = parse_sink_inputs(SINK_INPUTS);
+        let app = |name| app_pattern(name).unwrap();
+        assert!(inputs[0].belongs_to(&app("spotify")));
+        assert!(inputs[0].belongs_to(&app("SPOTIFY")));
+        assert!(!inputs[0].belongs_to(&app("firefox")));
+        assert!(inputs[1].belongs_to(&app("firefox")));
+        assert!(inputs[1].belongs_to(&app("firefox-bin")));
+        assert!(!inputs[1].belongs_to(&app("fire")));
+        assert!(inputs[1].belongs_to(&app("regex:^fire")));
+        assert!(!inputs.iter().any(|input| input.belongs_to(&app("discord"))));
+        assert!(app_pattern("regex:(").is_err());
+    }
+}
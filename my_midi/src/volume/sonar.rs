@@ -0,0 +1,65 @@
+// sonar.rs
+use std::sync::{Arc, Mutex};
+use crate::steelseries_sonar_api::{Mix, Sonar};
+use crate::volume::{VolumeBackend, VolumeResult};
+/*###############################################################################
+Sonar Backend
+    Channels are Sonar's own: master, game, chatRender, media, aux, chatCapture.
+    "streaming/game" is the game channel in the streaming mix (Streamer Mode only).
+###############################################################################*/
+pub struct SonarBackend {
+    sonar: Arc<Mutex<Sonar>>,
+}
+
+impl SonarBackend {
+    pub fn new(sonar: Arc<Mutex<Sonar>>) -> Self {
+        SonarBackend { sonar }
+    }
+}
+
+/// Splits "streaming/game" into the game channel and the streaming mix.
+fn split_mix(channel: &str) -> (&str, Mix) {
+    match channel.split_once('/') {
+        Some(("streaming", channel)) => (channel, Mix::Streaming),
+        Some(("monitoring", channel)) => (channel, Mix::Monitoring),
+        _ => (channel, Mix::Monitoring),
+    }
+}
+
+impl VolumeBackend for SonarBackend {
+    fn name(&self) -> &str {
+        "SteelSeries Sonar"
+    }
+
+    fn has_streaming_mix(&self) -> bool {
+        true
+    }
+
+    fn set_volume(&self, channel: &str, volume: f32) -> VolumeResult<()> {
+        let (channel, mix) = split_mix(channel);
+        Ok(self.sonar.lock().unwrap().set_mix_volume(channel, mix, volume)?)
+    }
+
+    fn get_volume(&self, channel: &str) -> VolumeResult<f32> {
+        let mut sonar = self.sonar.lock().unwrap();
+        match split_mix(channel) {
+            (channel, Mix::Monitoring) => Ok(sonar.get_volume(channel)?),
+            (channel, mix) => Ok(sonar.channel_state(channel, mix)?.volume),
+        }
+    }
+
+    fn set_mute(&self, channel: &str, muted: bool) -> VolumeResult<()> {
+        let (channel, mix) = split_mix(channel);
+        Ok(self.sonar.lock().unwrap().set_mute(channel, mix, muted)?)
+    }
+
+    fn is_muted(&self, channel: &str) -> VolumeResult<bool> {
+        let (channel, mix) = split_mix(channel);
+        Ok(self.sonar.lock().unwrap().channel_state(channel, mix)?.muted)
+    }
+
+    fn toggle_mute(&self, channel: &str) -> VolumeResult<bool> {
+        let (channel, mix) = split_mix(channel);
+        Ok(self.sonar.lock().unwrap().toggle_mute(channel, mix)?)
+    }
+}
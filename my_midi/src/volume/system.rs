@@ -0,0 +1,152 @@
+// system.rs
+use crate::volume::{VolumeBackend, VolumeResult};
+/*###############################################################################
+System Backend
+    The Windows mixer: "master" is the default playback device and "mic" the
+    default recording device, the same ones the taskbar volume slider controls.
+    Only available on Windows.
+###############################################################################*/
+pub struct SystemBackend;
+
+impl VolumeBackend for SystemBackend {
+    fn name(&self) -> &str {
+        "Windows mixer"
+    }
+
+    fn set_volume(&self, channel: &str, volume: f32) -> VolumeResult<()> {
+        if !(0.0..=1.0).contains(&volume) {
+            return Err(format!("Volume {} is outside 0.0 to 1.0", volume).into());
+        }
+        endpoint::set_volume(channel, volume)
+    }
+
+    fn get_volume(&self, channel: &str) -> VolumeResult<f32> {
+        endpoint::get_volume(channel)
+    }
+
+    fn set_mute(&self, channel: &str, muted: bool) -> VolumeResult<()> {
+        endpoint::set_mute(channel, muted)
+    }
+
+    fn is_muted(&self, channel: &str) -> VolumeResult<bool> {
+        endpoint::is_muted(channel)
+    }
+}
+
+#[cfg(windows)]
+mod endpoint {
+    use std::ptr;
+    use winapi::Interface;
+    use winapi::shared::minwindef::BOOL;
+    use winapi::shared::ntdef::HRESULT;
+    use winapi::um::combaseapi::{CoCreateInstance, CoInitializeEx, CoUninitialize, CLSCTX_ALL};
+    use winapi::um::endpointvolume::IAudioEndpointVolume;
+    use winapi::um::mmdeviceapi::{eCapture, eConsole, eRender, CLSID_MMDeviceEnumerator, IMMDevice, IMMDeviceEnumerator};
+    use winapi::um::objbase::COINIT_MULTITHREADED;
+    use crate::volume::VolumeResult;
+
+    fn check(hr: HRESULT, what: &str) -> VolumeResult<()> {
+        if hr < 0 {
+            Err(format!("{} failed (HRESULT {:#010x})", what, hr as u32).into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Runs `f` with the volume control of the default device for `channel`.
+    fn with_endpoint<T>(channel: &str, f: impl FnOnce(&IAudioEndpointVolume) -> VolumeResult<T>) -> VolumeResult<T> {
+        let flow = match channel {
+            "master" => eRender,
+            "mic" => eCapture,
+            _ => return Err(format!("Unknown channel '{}'. Use \"master\" or \"mic\".", channel).into()),
+        };
+        unsafe {
+            // S_FALSE (COM already set up on this thread) also needs a CoUninitialize
+            let initialized = CoInitializeEx(ptr::null_mut(), COINIT_MULTITHREADED) >= 0;
+            let result = (|| {
+                let mut enumerator: *mut IMMDeviceEnumerator = ptr::null_mut();
+                check(CoCreateInstance(
+                    &CLSID_MMDeviceEnumerator,
+                    ptr::null_mut(),
+                    CLSCTX_ALL,
+                    &IMMDeviceEnumerator::uuidof(),
+                    &mut enumerator as *mut *mut IMMDeviceEnumerator as *mut _,
+                ), "Creating the device enumerator")?;
+
+                let mut device: *mut IMMDevice = ptr::null_mut();
+                let hr = (*enumerator).GetDefaultAudioEndpoint(flow, eConsole, &mut device);
+                (*enumerator).Release();
+                check(hr, "Finding the default audio device")?;
+
+                let mut endpoint: *mut IAudioEndpointVolume = ptr::null_mut();
+                let hr = (*device).Activate(
+                    &IAudioEndpointVolume::uuidof(),
+                    CLSCTX_ALL,
+                    ptr::null_mut(),
+                    &mut endpoint as *mut *mut IAudioEndpointVolume as *mut _,
+                );
+                (*device).Release();
+                check(hr, "Opening the device's volume control")?;
+
+                let result = f(&*endpoint);
+                (*endpoint).Release();
+                result
+            })();
+            if initialized {
+                CoUninitialize();
+            }
+            result
+        }
+    }
+
+    pub fn set_volume(channel: &str, volume: f32) -> VolumeResult<()> {
+        with_endpoint(channel, |endpoint| unsafe {
+            check(endpoint.SetMasterVolumeLevelScalar(volume, ptr::null()), "Setting the volume")
+        })
+    }
+
+    pub fn get_volume(channel: &str) -> VolumeResult<f32> {
+        with_endpoint(channel, |endpoint| unsafe {
+            let mut volume = 0.0;
+            check(endpoint.GetMasterVolumeLevelScalar(&mut volume), "Reading the volume")?;
+            Ok(volume)
+        })
+    }
+
+    pub fn set_mute(channel: &str, muted: bool) -> VolumeResult<()> {
+        with_endpoint(channel, |endpoint| unsafe {
+            check(endpoint.SetMute(muted as BOOL, ptr::null()), "Muting")
+        })
+    }
+
+    pub fn is_muted(channel: &str) -> VolumeResult<bool> {
+        with_endpoint(channel, |endpoint| unsafe {
+            let mut muted: BOOL = 0;
+            check(endpoint.GetMute(&mut muted), "Reading the mute state")?;
+            Ok(muted != 0)
+        })
+    }
+}
+
+#[cfg(not(windows))]
+mod endpoint {
+    use crate::volume::VolumeResult;
+
+    const UNSUPPORTED: &str = "The system volume backend only works on Windows. Try \"pulse\" instead.";
+
+    pub fn set_volume(_channel: &str, _volume: f32) -> VolumeResult<()> {
+        Err(UNSUPPORTED.into())
+    }
+
+    pub fn get_volume(_channel: &str) -> VolumeResult<f32> {
+        Err(UNSUPPORTED.into())
+    }
+
+    pub fn set_mute(_channel: &str, _muted: bool) -> VolumeResult<()> {
+        Err(UNSUPPORTED.into())
+    }
+
+    pub fn is_muted(_channel: &str) -> VolumeResult<bool> {
+        Err(UNSUPPORTED.into())
+    }
+}
@@ -0,0 +1,175 @@
+// volume_throttle.rs
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use crate::executor::Executor;
+/*###############################################################################
+Volume Throttle
+    Turning a knob sends dozens of CC messages a second. Sending a volume request
+    for every one of them floods the audio server and makes the volume lag behind
+    the knob. Instead, each lane (one per knob target, e.g. "volume:monitoring:game")
+    keeps only the latest request and runs at most one per interval:
+        - the first request after a pause runs right away,
+        - requests that arrive while one is in flight or cooling down replace
+          each other, and only the newest runs once the interval has passed,
+        - the last request always runs, so the volume ends up where the knob stopped.
+    Requests run on the executor, in the lane's own executor lane. A request
+    that panics doesn't stop the ones after it.
+###############################################################################*/
+pub const DEFAULT_UPDATES_PER_SECOND: f32 = 20.0;
+/// `volume_updates_per_second` is kept within these.
+const MIN_UPDATES_PER_SECOND: f32 = 1.0;
+const MAX_UPDATES_PER_SECOND: f32 = 1000.0;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+#[derive(Default)]
+struct Slot {
+    /// The newest request that hasn't run yet.
+    latest: Option<Job>,
+    /// Whether a job is already running for this lane and will pick up `latest`.
+    scheduled: bool,
+}
+
+pub struct VolumeThrottle {
+    executor: Executor,
+    interval: Duration,
+    slots: Mutex<HashMap<String, Arc<Mutex<Slot>>>>,
+}
+
+impl VolumeThrottle {
+    /// Runs at most `updates_per_second` requests a second in each lane.
+    pub fn new(executor: Executor, updates_per_second: f32) -> Self {
+        let updates_per_second = if updates_per_second > 0.0 {
+            updates_per_second.clamp(MIN_UPDATES_PER_SECOND, MAX_UPDATES_PER_SECOND)
+        } else {
+            DEFAULT_UPDATES_PER_SECOND
+        };
+        VolumeThrottle {
+            executor,
+            interval: Duration::from_secs_f32(1.0 / updates_per_second),
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Runs `job` in `lane`, unless a newer job for the same lane comes in before
+    /// it gets its turn. Returns right away.
+    pub fn run_latest(&self, lane: &str, job: impl FnOnce() + Send + 'static) {
+        let slot = self.slots.lock().unwrap().entry(lane.to_string()).or_default().clone();
+        {
+            let mut state = slot.lock().unwrap();
+            state.latest = Some(Box::new(job));
+            if state.scheduled {
+                return;
+            }
+            state.scheduled = true;
+        }
+
+        let interval = self.interval;
+        self.executor.spawn_in_lane(lane, move || {
+            loop {
+                let job = {
+                    let mut state = slot.lock().unwrap();
+                    match state.latest.take() {
+                        Some(job) => job,
+                        None => {
+                            state.scheduled = false;
+                            return;
+                        },
+                    }
+                };
+                // Catch it here, so `scheduled` is still reset once the lane runs dry
+                if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                    println!("A volume update panicked.");
+                }
+                thread::sleep(interval);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    /// Waits up to a second for `done`.
+    fn wait_for(done: impl Fn() -> bool) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(1) {
+            if done() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        false
+    }
+
+    fn recorder() -> (Arc<Mutex<Vec<u32>>>, impl Fn(u32) -> Job) {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let sent_for_jobs = sent.clone();
+        (sent, move |value| {
+            let sent = sent_for_jobs.clone();
+            Box::new(move || sent.lock().unwrap().push(value))
+        })
+    }
+
+    #[test]
+    fn a_burst_runs_the_first_and_last_request_only() {
+        let throttle = VolumeThrottle::new(Executor::new(), 10.0);
+        let (sent, job) = recorder();
+        for value in 0..50 {
+            throttle.run_latest("volume:game", job(value));
+        }
+        assert!(wait_for(|| sent.lock().unwrap().last() == Some(&49)));
+        thread::sleep(Duration::from_millis(150));
+        let sent = sent.lock().unwrap();
+        assert!(sent.len() <= 2, "{:?}", sent);
+    }
+
+    #[test]
+    fn the_final_value_is_sent_after_a_pause() {
+        let throttle = VolumeThrottle::new(Executor::new(), 20.0);
+        let (sent, job) = recorder();
+        throttle.run_latest("volume:game", job(1));
+        assert!(wait_for(|| *sent.lock().unwrap() == vec![1]));
+        // Comes in during the cool-down, so it has to wait for it
+        throttle.run_latest("volume:game", job(2));
+        assert!(wait_for(|| *sent.lock().unwrap() == vec![1, 2]));
+        // After the lane ran dry, a new request runs right away again
+        thread::sleep(Duration::from_millis(100));
+        throttle.run_latest("volume:game", job(3));
+        assert!(wait_for(|| *sent.lock().unwrap() == vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn lanes_do_not_wait_for_each_other() {
+        let throttle = VolumeThrottle::new(Executor::new(), 20.0);
+        let (sent, job) = recorder();
+        throttle.run_latest("volume:slow", move || thread::sleep(Duration::from_millis(500)));
+        throttle.run_latest("volume:game", job(1));
+        let start = Instant::now();
+        assert!(wait_for(|| *sent.lock().unwrap() == vec![1]));
+        assert!(start.elapsed() < Duration::from_millis(400));
+    }
+
+    #[test]
+    fn a_panicking_request_does_not_block_its_lane() {
+        let throttle = VolumeThrottle::new(Executor::new(), 1000.0);
+        let (sent, job) = recorder();
+        throttle.run_latest("volume:game", || panic!("backend bug"));
+        thread::sleep(Duration::from_millis(50));
+        throttle.run_latest("volume:game", job(1));
+        assert!(wait_for(|| *sent.lock().unwrap() == vec![1]));
+    }
+
+    #[test]
+    fn keeps_the_rate_sane() {
+        let interval_ms = |updates_per_second: f32| VolumeThrottle::new(Executor::new(), updates_per_second).interval.as_millis();
+        assert_eq!(interval_ms(1e-9), 1000);
+        assert_eq!(interval_ms(1e9), 1);
+        assert_eq!(interval_ms(-3.0), 50);
+    }
+}
@@ -0,0 +1,591 @@
+// window_watcher.rs
+use std::error::Error;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+use serde::Deserialize;
+use crate::osd;
+use crate::port_select::NamePattern;
+/*###############################################################################
+Automatic Profile Switching
+    Picks the profile for whatever window you're in, so starting Genshin also
+    switches the pads to the Genshin profile. Rules go in settings.json:
+        "auto_profile": {
+            "rules": [
+                { "title": "exact:Genshin Impact", "profile": "Genshin" },
+                { "process": "ZenlessZoneZero.exe", "profile": "ZenlessZoneZero" }
+            ]
+        }
+    A rule looks at the focused window's
+        process  name of the program that owns it, like ZenlessZoneZero.exe
+        class    window class (the second part of WM_CLASS on Linux)
+        title    title
+    Every part a rule has must match, and the first rule that matches wins. Text
+    matches when it is part of the name, ignoring case. "exact:" and "regex:" work
+    like they do for ports (see port_select.rs).
+    Games running as administrator (Genshin does) hide their process name from
+    programs that aren't, so match those on the title or class instead.
+
+    Where the focused window comes from:
+        Windows  The foreground window.
+        Linux    _NET_ACTIVE_WINDOW, read with `xprop` (X11, including games
+                 running under XWayland). Without X11 there's no way to ask which
+                 window is focused, so `process` rules match any running process
+                 instead and the rest are ignored.
+    The window is checked every `poll_ms` milliseconds (default 1000). When a
+    rule for another profile starts matching, every device switches to it, shown
+    like a profile pad change (on the OSD if it's on, see osd.rs). Windows no rule
+    matches (a browser, Discord) change nothing.
+
+    Manual override
+        The profile pad keeps working. What it picks sticks until you go to a
+        window a rule matches again, say by alt-tabbing out of the game and back.
+        Bind { "type": "toggle_auto_profile" } to a pad to pause automatic
+        switching altogether, and press it again to pick it back up.
+###############################################################################*/
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AutoProfileSettings {
+    pub rules: Vec<RuleSettings>,
+    /// How often the focused window is checked.
+    pub poll_ms: u64,
+}
+
+impl Default for AutoProfileSettings {
+    fn default() -> Self {
+        AutoProfileSettings { rules: Vec::new(), poll_ms: 1000 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RuleSettings {
+    #[serde(default)]
+    pub process: Option<String>,
+    #[serde(default)]
+    pub class: Option<String>,
+    #[serde(default)]
+    pub title: Option<String>,
+    pub profile: String,
+}
+
+/// What we know about the focused window. Parts that can't be found out are empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Window {
+    pub process: String,
+    pub class: String,
+    pub title: String,
+}
+
+/// Where the focused window comes from. Tests use a fake one.
+pub trait WindowSource: Send {
+    /// The focused window, or None if there's no way to tell.
+    fn focused(&mut self) -> Result<Option<Window>, Box<dyn Error>>;
+
+    /// The names of the running processes. Only asked when `focused` can't tell.
+    fn processes(&mut self) -> Result<Vec<String>, Box<dyn Error>> {
+        Ok(Vec::new())
+    }
+}
+
+#[derive(Debug)]
+struct Rule {
+    process: Option<NamePattern>,
+    class: Option<NamePattern>,
+    title: Option<NamePattern>,
+    profile: String,
+}
+
+impl Rule {
+    fn new(settings: &RuleSettings, profiles: &[&str]) -> Result<Self, String> {
+        if !profiles.contains(&settings.profile.as_str()) {
+            return Err(format!("The auto_profile rule for '{}' names no profile. The profiles are: {}.", settings.profile, profiles.join(", ")));
+        }
+        let pattern = |text: &Option<String>| text.as_deref().map(NamePattern::parse).transpose();
+        let rule = Rule { process: pattern(&settings.process)?, class: pattern(&settings.class)?, title: pattern(&settings.title)?, profile: settings.profile.clone() };
+        if rule.process.is_none() && rule.class.is_none() && rule.title.is_none() {
+            return Err(format!("The auto_profile rule for '{}' needs a process, class or title to match.", rule.profile));
+        }
+        Ok(rule)
+    }
+
+    fn matches(&self, window: &Window) -> bool {
+        let part = |pattern: &Option<NamePattern>, text: &str| pattern.as_ref().is_none_or(|pattern| pattern.matches(text));
+        part(&self.process, &window.process) && part(&self.class, &window.class) && part(&self.title, &window.title)
+    }
+}
+
+/// The rules from the settings, in order.
+#[derive(Debug)]
+pub struct Rules(Vec<Rule>);
+
+impl Rules {
+    /// Fails on a bad pattern, or a rule for a profile that isn't in `profiles`.
+    pub fn new(settings: &[RuleSettings], profiles: &[&str]) -> Result<Self, String> {
+        settings.iter().map(|rule| Rule::new(rule, profiles)).collect::<Result<_, _>>().map(Rules)
+    }
+
+    /// The profile for what `source` says is focused (or running), if a rule matches.
+    pub fn profile_for(&self, source: &mut dyn WindowSource) -> Result<Option<&str>, Box<dyn Error>> {
+        let rule = match source.focused()? {
+            Some(window) => self.0.iter().find(|rule| rule.matches(&window)),
+            None => {
+                let processes = source.processes()?;
+                self.0.iter().find(|rule| match &rule.process {
+                    Some(pattern) => processes.iter().any(|process| pattern.matches(process)),
+                    None => false,
+                })
+            },
+        };
+        Ok(rule.map(|rule| rule.profile.as_str()))
+    }
+}
+
+/// Called with the profile to switch to. Returns false once it no longer wants to hear about it.
+type Subscriber = Box<dyn FnMut(&str) -> bool + Send>;
+
+#[derive(Default)]
+struct Switcher {
+    /// The profile the rules picked last, or None if no rule matched.
+    current: Option<String>,
+    paused: bool,
+    subscribers: Vec<Subscriber>,
+}
+
+impl Switcher {
+    /// Takes the profile the rules picked now. Returns true if the subscribers were
+    /// told to switch to it.
+    fn update(&mut self, profile: Option<&str>) -> bool {
+        if self.current.as_deref() == profile {
+            return false;
+        }
+        self.current = profile.map(str::to_string);
+        !self.paused && self.publish()
+    }
+
+    fn subscribe(&mut self, mut on_switch: Subscriber) {
+        if let (Some(profile), false) = (&self.current, self.paused) {
+            if !on_switch(profile) {
+                return;
+            }
+        }
+        self.subscribers.push(on_switch);
+    }
+
+    /// Pauses, or picks back up and switches to what the rules picked meanwhile.
+    /// Returns whether it is paused now.
+    fn toggle_paused(&mut self) -> bool {
+        self.paused = !self.paused;
+        if !self.paused {
+            self.publish();
+        }
+        self.paused
+    }
+
+    fn publish(&mut self) -> bool {
+        let Some(profile) = &self.current else {
+            return false;
+        };
+        // Drop subscribers that have gone away
+        self.subscribers.retain_mut(|on_switch| on_switch(profile));
+        true
+    }
+}
+
+static SWITCHER: OnceLock<Mutex<Switcher>> = OnceLock::new();
+
+/// Starts watching the focused window, if there are any rules. `profiles` are the
+/// names of the registered profiles. Call it once, at startup.
+pub fn install(settings: &AutoProfileSettings, profiles: &[&str]) -> Result<(), Box<dyn Error>> {
+    if settings.rules.is_empty() {
+        return Ok(());
+    }
+    // Check the rules up front so a typo stops MyMIDI right away
+    let rules = Rules::new(&settings.rules, profiles)?;
+    let source = match default_source() {
+        Ok(source) => source,
+        Err(e) => {
+            println!("Automatic profile switching is off: {}", e);
+            return Ok(());
+        },
+    };
+    if SWITCHER.set(Mutex::new(Switcher::default())).is_err() {
+        println!("Automatic profile switching was already set up.");
+        return Ok(());
+    }
+    let interval = Duration::from_millis(settings.poll_ms.max(50));
+    thread::spawn(move || run(rules, source, interval));
+    Ok(())
+}
+
+/// Calls `on_switch` with the profile to switch to, now if the rules already
+/// picked one and again every time they pick another. Does nothing if automatic
+/// switching is off. Return false from `on_switch` to stop.
+pub fn subscribe(on_switch: impl FnMut(&str) -> bool + Send + 'static) {
+    if let Some(switcher) = SWITCHER.get() {
+        switcher.lock().unwrap().subscribe(Box::new(on_switch));
+    }
+}
+
+/// Pauses or resumes automatic switching. Returns whether it is paused now, or
+/// None if it isn't set up.
+pub fn toggle_paused() -> Option<bool> {
+    SWITCHER.get().map(|switcher| switcher.lock().unwrap().toggle_paused())
+}
+
+fn run(rules: Rules, mut source: Box<dyn WindowSource>, interval: Duration) {
+    let Some(switcher) = SWITCHER.get() else { return };
+    let mut failing = false;
+    loop {
+        match rules.profile_for(source.as_mut()) {
+            Ok(profile) => {
+                failing = false;
+                if switcher.lock().unwrap().update(profile) {
+                    osd::status("Profile Changed", &format!("{} profile is now active.", profile.unwrap_or_default()));
+                }
+            },
+            // Only say so once, not every poll
+            Err(e) if !failing => {
+                println!("Could not check the focused window: {}", e);
+                failing = true;
+            },
+            Err(_) => {},
+        }
+        thread::sleep(interval);
+    }
+}
+
+#[cfg(windows)]
+fn default_source() -> Result<Box<dyn WindowSource>, Box<dyn Error>> {
+    Ok(Box::new(foreground::ForegroundSource))
+}
+
+#[cfg(target_os = "linux")]
+fn default_source() -> Result<Box<dyn WindowSource>, Box<dyn Error>> {
+    let source = linux::LinuxSource::new();
+    if !source.has_x11() {
+        println!("Can't see the focused window without X11 (xprop). Matching auto_profile process rules against running processes instead.");
+    }
+    Ok(Box::new(source))
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+fn default_source() -> Result<Box<dyn WindowSource>, Box<dyn Error>> {
+    Err("the focused window can't be checked on this system".into())
+}
+
+#[cfg(windows)]
+mod foreground {
+    use std::error::Error;
+    use winapi::shared::minwindef::{DWORD, FALSE};
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::processthreadsapi::OpenProcess;
+    use winapi::um::winbase::QueryFullProcessImageNameW;
+    use winapi::um::winnt::PROCESS_QUERY_LIMITED_INFORMATION;
+    use winapi::um::winuser::{GetClassNameW, GetForegroundWindow, GetWindowTextW, GetWindowThreadProcessId};
+    use super::{Window, WindowSource};
+
+    pub struct ForegroundSource;
+
+    impl WindowSource for ForegroundSource {
+        fn focused(&mut self) -> Result<Option<Window>, Box<dyn Error>> {
+            unsafe {
+                let hwnd = GetForegroundWindow();
+                // Nothing has focus for a moment while switching windows
+                if hwnd.is_null() {
+                    return Ok(Some(Window::default()));
+                }
+                let mut buffer = [0u16; 512];
+                let length = GetClassNameW(hwnd, buffer.as_mut_ptr(), buffer.len() as i32);
+                let class = String::from_utf16_lossy(&buffer[..length.max(0) as usize]);
+                let length = GetWindowTextW(hwnd, buffer.as_mut_ptr(), buffer.len() as i32);
+                let title = String::from_utf16_lossy(&buffer[..length.max(0) as usize]);
+                let mut pid: DWORD = 0;
+                GetWindowThreadProcessId(hwnd, &mut pid);
+                Ok(Some(Window { process: process_name(pid).unwrap_or_default(), class, title }))
+            }
+        }
+    }
+
+    /// The file name of the process's exe. None if we aren't allowed to look,
+    /// which is the case for processes running as administrator.
+    unsafe fn process_name(pid: DWORD) -> Option<String> {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, pid);
+        if process.is_null() {
+            return None;
+        }
+        let mut buffer = [0u16; 1024];
+        let mut length = buffer.len() as DWORD;
+        let succeeded = QueryFullProcessImageNameW(process, 0, buffer.as_mut_ptr(), &mut length) != 0;
+        CloseHandle(process);
+        if !succeeded {
+            return None;
+        }
+        let path = String::from_utf16_lossy(&buffer[..length as usize]);
+        path.rsplit('\\').next().map(str::to_string)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::error::Error;
+    use std::fs;
+    use std::process::Command;
+    use super::{Window, WindowSource};
+
+    pub struct LinuxSource {
+        x11: bool,
+    }
+
+    impl LinuxSource {
+        pub fn new() -> Self {
+            LinuxSource { x11: xprop(&["-root", "_NET_ACTIVE_WINDOW"]).is_ok() }
+        }
+
+        pub fn has_x11(&self) -> bool {
+            self.x11
+        }
+    }
+
+    impl WindowSource for LinuxSource {
+        fn focused(&mut self) -> Result<Option<Window>, Box<dyn Error>> {
+            if !self.x11 {
+                return Ok(None);
+            }
+            let Some(id) = parse_active_window(&xprop(&["-root", "_NET_ACTIVE_WINDOW"])?) else {
+                return Ok(Some(Window::default()));
+            };
+            let properties = xprop(&["-id", &id, "WM_CLASS", "_NET_WM_NAME", "WM_NAME", "_NET_WM_PID"])?;
+            let (mut window, pid) = parse_window(&properties);
+            if let Some(pid) = pid {
+                window.process = process_name(pid).unwrap_or_default();
+            }
+            Ok(Some(window))
+        }
+
+        fn processes(&mut self) -> Result<Vec<String>, Box<dyn Error>> {
+            let mut names = Vec::new();
+            for entry in fs::read_dir("/proc")? {
+                let name = entry?.file_name();
+                if let Some(pid) = name.to_str().and_then(|name| name.parse().ok()) {
+                    // Processes can exit while we look
+                    names.extend(process_name(pid));
+                }
+            }
+            Ok(names)
+        }
+    }
+
+    fn xprop(args: &[&str]) -> Result<String, Box<dyn Error>> {
+        let output = Command::new("xprop").args(args).output().map_err(|e| format!("xprop: {}", e))?;
+        if !output.status.success() {
+            return Err(format!("xprop failed: {}", String::from_utf8_lossy(&output.stderr).trim()).into());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// The window id in "_NET_ACTIVE_WINDOW(WINDOW): window id # 0x3a00007",
+    /// or None when no window has focus.
+    pub(super) fn parse_active_window(output: &str) -> Option<String> {
+        let id = output.split('#').nth(1)?.split(',').next()?.trim();
+        (id.starts_with("0x") && id != "0x0").then(|| id.to_string())
+    }
+
+    /// The class and title from xprop's output, and the window's process id.
+    pub(super) fn parse_window(output: &str) -> (Window, Option<u32>) {
+        let mut window = Window::default();
+        let mut pid = None;
+        let mut has_net_wm_name = false;
+        for line in output.lines() {
+            let Some((name, value)) = line.split_once(" = ") else { continue };
+            match name.split('(').next().unwrap_or_default() {
+                // "instance", "Class"
+                "WM_CLASS" => window.class = strings(value).pop().unwrap_or_default(),
+                "_NET_WM_NAME" => {
+                    window.title = strings(value).pop().unwrap_or_default();
+                    has_net_wm_name = true;
+                },
+                // The old title property, only used if the UTF-8 one isn't there
+                "WM_NAME" if !has_net_wm_name => window.title = strings(value).pop().unwrap_or_default(),
+                "_NET_WM_PID" => pid = value.trim().parse().ok(),
+                _ => {},
+            }
+        }
+        (window, pid)
+    }
+
+    /// The quoted strings in an xprop value, unescaped.
+    fn strings(value: &str) -> Vec<String> {
+        let mut strings = Vec::new();
+        let mut chars = value.chars();
+        while chars.any(|c| c == '"') {
+            let mut string = String::new();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => string.extend(chars.next()),
+                    c => string.push(c),
+                }
+            }
+            strings.push(string);
+        }
+        strings
+    }
+
+    fn process_name(pid: u32) -> Option<String> {
+        let cmdline = fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+        match name_from_cmdline(&cmdline) {
+            Some(name) => Some(name),
+            None => fs::read_to_string(format!("/proc/{}/comm", pid)).ok().map(|comm| comm.trim().to_string()),
+        }
+    }
+
+    /// The file name of the program in a /proc cmdline. Games running in Wine or
+    /// Proton show up with their Windows path, like "C:\Games\Genshin Impact\GenshinImpact.exe".
+    pub(super) fn name_from_cmdline(cmdline: &[u8]) -> Option<String> {
+        let program = cmdline.split(|byte| *byte == 0).next()?;
+        let program = String::from_utf8_lossy(program);
+        let name = program.rsplit(['/', '\\']).next()?;
+        (!name.is_empty()).then(|| name.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    struct FakeSource {
+        window: Option<Window>,
+        processes: Vec<String>,
+    }
+
+    impl WindowSource for FakeSource {
+        fn focused(&mut self) -> Result<Option<Window>, Box<dyn Error>> {
+            Ok(self.window.clone())
+        }
+
+        fn processes(&mut self) -> Result<Vec<String>, Box<dyn Error>> {
+            Ok(self.processes.clone())
+        }
+    }
+
+    fn window(process: &str, class: &str, title: &str) -> FakeSource {
+        FakeSource { window: Some(Window { process: process.to_string(), class: class.to_string(), title: title.to_string() }), processes: Vec::new() }
+    }
+
+    const PROFILES: &[&str] = &["Default", "Genshin", "Genshin (running)", "Zenless Zone Zero"];
+
+    fn rules(json: &str) -> Rules {
+        let settings: AutoProfileSettings = serde_json::from_str(json).unwrap();
+        Rules::new(&settings.rules, PROFILES).unwrap()
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = rules(r#"{ "rules": [
+            { "process": "exact:ZenlessZoneZero.exe", "profile": "Zenless Zone Zero" },
+            { "class": "UnityWndClass", "title": "regex:^Genshin", "profile": "Genshin" },
+            { "class": "UnityWndClass", "profile": "Default" }
+        ] }"#);
+        assert_eq!(rules.profile_for(&mut window("ZenlessZoneZero.exe", "UnityWndClass", "ZenlessZoneZero")).unwrap(), Some("Zenless Zone Zero"));
+        assert_eq!(rules.profile_for(&mut window("", "UnityWndClass", "Genshin Impact")).unwrap(), Some("Genshin"));
+        assert_eq!(rules.profile_for(&mut window("", "unitywndclass", "Some other game")).unwrap(), Some("Default"));
+        assert_eq!(rules.profile_for(&mut window("firefox", "firefox", "Genshin Impact wiki")).unwrap(), None);
+    }
+
+    #[test]
+    fn without_a_focused_window_process_rules_look_at_running_processes() {
+        let rules = rules(r#"{ "rules": [
+            { "title": "Genshin", "profile": "Genshin" },
+            { "process": "GenshinImpact.exe", "profile": "Genshin (running)" }
+        ] }"#);
+        let mut source = FakeSource { window: None, processes: vec![String::from("bash"), String::from("GenshinImpact.exe")] };
+        assert_eq!(rules.profile_for(&mut source).unwrap(), Some("Genshin (running)"));
+        source.processes.pop();
+        assert_eq!(rules.profile_for(&mut source).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_rules_that_match_everything() {
+        let settings: AutoProfileSettings = serde_json::from_str(r#"{ "rules": [{ "profile": "Genshin" }] }"#).unwrap();
+        assert!(Rules::new(&settings.rules, PROFILES).is_err());
+    }
+
+    #[test]
+    fn rejects_rules_for_unknown_profiles() {
+        let settings: AutoProfileSettings = serde_json::from_str(r#"{ "rules": [{ "title": "Genshin Impact", "profile": "genshin" }] }"#).unwrap();
+        let error = Rules::new(&settings.rules, PROFILES).unwrap_err();
+        assert!(error.contains("'genshin'"), "{}", error);
+    }
+
+    fn recording_subscriber(switcher: &mut Switcher) -> Arc<Mutex<Vec<String>>> {
+        let switches = Arc::new(Mutex::new(Vec::new()));
+        let recorded = switches.clone();
+        switcher.subscribe(Box::new(move |profile| {
+            recorded.lock().unwrap().push(profile.to_string());
+            true
+        }));
+        switches
+    }
+
+    #[test]
+    fn only_switches_when_another_rule_matches() {
+        let mut switcher = Switcher::default();
+        let switches = recording_subscriber(&mut switcher);
+        assert!(switcher.update(Some("Genshin")));
+        assert!(!switcher.update(Some("Genshin")));
+        // Leaving the game for a window no rule matches keeps its profile
+        assert!(!switcher.update(None));
+        assert!(switcher.update(Some("Genshin")));
+        assert_eq!(*switches.lock().unwrap(), vec!["Genshin", "Genshin"]);
+    }
+
+    #[test]
+    fn paused_switching_catches_up_when_resumed() {
+        let mut switcher = Switcher::default();
+        switcher.update(Some("Genshin"));
+        assert!(switcher.toggle_paused());
+        let switches = recording_subscriber(&mut switcher);
+        assert!(!switcher.update(Some("Zenless Zone Zero")));
+        assert!(switches.lock().unwrap().is_empty());
+        assert!(!switcher.toggle_paused());
+        assert_eq!(*switches.lock().unwrap(), vec!["Zenless Zone Zero"]);
+    }
+
+    #[test]
+    fn new_subscribers_hear_the_current_profile_and_can_leave() {
+        let mut switcher = Switcher::default();
+        switcher.update(Some("Genshin"));
+        let switches = recording_subscriber(&mut switcher);
+        assert_eq!(*switches.lock().unwrap(), vec!["Genshin"]);
+        switcher.subscribe(Box::new(|_| false));
+        assert_eq!(switcher.subscribers.len(), 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn parses_xprop_output() {
+        assert_eq!(linux::parse_active_window("_NET_ACTIVE_WINDOW(WINDOW): window id # 0x3a00007\n"), Some(String::from("0x3a00007")));
+        assert_eq!(linux::parse_active_window("_NET_ACTIVE_WINDOW(WINDOW): window id # 0x0\n"), None);
+        let output = concat!(
+            "WM_CLASS(STRING) = \"genshinimpact.exe\", \"GenshinImpact.exe\"\n",
+            "_NET_WM_NAME(UTF8_STRING) = \"Genshin \\\"Impact\\\"\"\n",
+            "WM_NAME(STRING) = \"Genshin\"\n",
+            "_NET_WM_PID(CARDINAL) = 4242\n",
+        );
+        let (window, pid) = linux::parse_window(output);
+        assert_eq!(window, Window { process: String::new(), class: String::from("GenshinImpact.exe"), title: String::from("Genshin \"Impact\"") });
+        assert_eq!(pid, Some(4242));
+        let (window, pid) = linux::parse_window("WM_NAME(STRING) = \"xterm\"\n_NET_WM_PID:  not found.\n");
+        assert_eq!(window.title, "xterm");
+        assert_eq!(pid, None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn names_processes_after_their_program() {
+        assert_eq!(linux::name_from_cmdline(b"/usr/bin/steam\0-silent\0"), Some(String::from("steam")));
+        assert_eq!(linux::name_from_cmdline(b"C:\\Games\\Genshin Impact\\GenshinImpact.exe\0"), Some(String::from("GenshinImpact.exe")));
+        assert_eq!(linux::name_from_cmdline(b""), None);
+    }
+}